@@ -0,0 +1,187 @@
+//! Combining metrics on the client before they are sent.
+
+use std::mem;
+
+use collections::hashmap::{HashMap, HashSet};
+
+use metric;
+use metric::{Key, Metric, MetricKind};
+
+use time;
+
+
+/** Aggregates metrics on the client, so that fewer lines are sent.
+
+Counters are summed, scaled by their sample rate, and gauges keep their last
+value (or the sum of relative updates made since it was last sent). Sets keep
+each distinct member once. Timers, histograms and distributions are kept as
+they are. Samples and set members are kept up to a limit per series, past
+which they are dropped and counted.
+
+Sending the drained metrics gives the same result in the server's buckets as
+sending every metric as it came, apart from the dropped samples. Metrics
+carrying their own timestamp aren't aggregated.
+
+The aggregator has no timer of its own. Its owner checks `is_due` (or
+`ns_until_due`) and drains it; a `Client` does so whenever a metric is sent
+or `flush_if_due` is called.
+*/
+pub struct Aggregator {
+    counters: HashMap<Key, f64>,
+    gauges: HashMap<Key, (MetricKind, f64)>,
+    samples: HashMap<Key, ~[(MetricKind, f64)]>,
+    sets: HashMap<Key, HashSet<~str>>,
+
+    max_samples: uint, // per series
+    dropped_samples: uint,
+
+    interval_ns: u64,
+    last_drain_ns: u64
+}
+
+
+impl Aggregator {
+    /// Create an aggregator due to be drained every `interval_ms`, keeping up
+    /// to `max_samples` samples for each timer, histogram, distribution or
+    /// set.
+    pub fn new(interval_ms: u64, max_samples: uint) -> Aggregator {
+        Aggregator {
+            counters: HashMap::new(),
+            gauges: HashMap::new(),
+            samples: HashMap::new(),
+            sets: HashMap::new(),
+
+            max_samples: max_samples,
+            dropped_samples: 0,
+
+            interval_ns: interval_ms * 1000000,
+            last_drain_ns: time::precise_time_ns()
+        }
+    }
+
+    /// Number of samples dropped because their series was full.
+    pub fn dropped_samples(&self) -> uint {
+        self.dropped_samples
+    }
+
+    /// Whether the aggregated metrics have been held for the whole interval.
+    pub fn is_due(&self) -> bool {
+        self.ns_until_due() == 0
+    }
+
+    /// Nanoseconds left until the aggregated metrics have been held for the
+    /// whole interval, or 0 if they already have.
+    pub fn ns_until_due(&self) -> u64 {
+        let held_ns = time::precise_time_ns() - self.last_drain_ns;
+
+        if held_ns >= self.interval_ns { 0 } else { self.interval_ns - held_ns }
+    }
+
+    /// Whether nothing has been aggregated since the last drain.
+    pub fn is_empty(&self) -> bool {
+        self.counters.is_empty() && self.gauges.is_empty() &&
+            self.samples.is_empty() && self.sets.is_empty()
+    }
+
+    /// Aggregate `metric`, handing it back if it should be sent as it is.
+    pub fn add(&mut self, metric: Metric) -> Option<Metric> {
+        if metric.timestamp.is_some() {
+            return Some(metric);
+        }
+
+        let Metric { kind, name, value, member, tags, .. } = metric;
+        let key = Key::new(name, tags);
+
+        match kind {
+            metric::Counter(sample_rate) => {
+                let value = value / sample_rate;
+                self.counters.insert_or_update_with(key, value, |_, total| *total += value);
+            },
+            metric::Gauge => {
+                self.gauges.insert(key, (metric::Gauge, value));
+            },
+            // A relative update applies to whatever is already held, keeping
+            // its kind.
+            metric::GaugeDelta => {
+                self.gauges.insert_or_update_with(key, (metric::GaugeDelta, value), |_, gauge| {
+                    let (kind, total) = *gauge;
+                    *gauge = (kind, total + value);
+                });
+            },
+            // Sending a member again within the interval doesn't change the
+            // set, so each is only kept once.
+            metric::Set => {
+                let max_samples = self.max_samples;
+                let member = member.unwrap_or(~"");
+                let members = self.sets.find_or_insert_with(key, |_| HashSet::new());
+
+                if !members.contains(&member) {
+                    if members.len() < max_samples {
+                        members.insert(member);
+                    } else {
+                        self.dropped_samples += 1;
+                    }
+                }
+            },
+            _ => {
+                let max_samples = self.max_samples;
+                let samples = self.samples.find_or_insert_with(key, |_| ~[]);
+
+                if samples.len() < max_samples {
+                    samples.push((kind, value));
+                } else {
+                    self.dropped_samples += 1;
+                }
+            }
+        }
+
+        None
+    }
+
+    /// Take everything aggregated so far, as the metrics to send.
+    pub fn drain(&mut self) -> ~[Metric] {
+        let mut metrics = ~[];
+
+        let counters = mem::replace(&mut self.counters, HashMap::new());
+        for (key, total) in counters.move_iter() {
+            metrics.push(to_metric(metric::Counter(1.0), key, total));
+        }
+
+        let gauges = mem::replace(&mut self.gauges, HashMap::new());
+        for (key, (kind, value)) in gauges.move_iter() {
+            metrics.push(to_metric(kind, key, value));
+        }
+
+        let samples = mem::replace(&mut self.samples, HashMap::new());
+        for (key, values) in samples.move_iter() {
+            for &(kind, value) in values.iter() {
+                metrics.push(to_metric(kind, key.clone(), value));
+            }
+        }
+
+        let sets = mem::replace(&mut self.sets, HashMap::new());
+        for (key, members) in sets.move_iter() {
+            for member in members.move_iter() {
+                let mut metric = to_metric(metric::Set, key.clone(), 0.0);
+                metric.member = Some(member);
+                metrics.push(metric);
+            }
+        }
+
+        self.last_drain_ns = time::precise_time_ns();
+
+        metrics
+    }
+}
+
+
+fn to_metric(kind: MetricKind, key: Key, value: f64) -> Metric {
+    Metric {
+        kind: kind,
+        name: key.name,
+        value: value,
+        member: None,
+        tags: key.tags,
+        timestamp: None
+    }
+}
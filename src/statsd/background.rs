@@ -0,0 +1,141 @@
+//! Sending metrics from a background task, off the caller's hot path.
+
+use std::comm::{Sender, Receiver, SyncSender, channel, sync_channel};
+use std::io::timer::Timer;
+use std::sync::atomics::{AtomicUint, SeqCst};
+
+use sync::Arc;
+
+use client::Client;
+use metric::Metric;
+use sink::MetricSink;
+
+
+/// Default number of metrics a `BackgroundClient` queues up before it starts
+/// dropping them.
+pub static DEFAULT_QUEUE_SIZE: uint = 1024;
+
+
+/// What the background task is asked to do.
+enum Command {
+    SendMetric(Metric),
+    FlushClient,
+    Shutdown(Sender<()>)
+}
+
+
+/** A handle to a `Client` owned by a background task.
+
+Metrics are put on a bounded queue, and the formatting and sending is left to
+the background task. When the queue is full, metrics are dropped and counted
+rather than blocking the caller.
+
+Handles are cheap to clone, and can be sent to other tasks. The background
+task flushes its client and exits once `shutdown` is called or every handle
+has been dropped. Buffered or aggregated metrics are sent as soon as they are
+due, even if no more metrics are queued.
+*/
+#[deriving(Clone)]
+pub struct BackgroundClient {
+    queue: SyncSender<Command>,
+    dropped: Arc<AtomicUint>,
+    default_sample_rate: f64
+}
+
+
+impl BackgroundClient {
+    /// Move `client` into a new background task, queueing up to `queue_size`
+    /// metrics for it.
+    pub fn new(client: Client, queue_size: uint) -> BackgroundClient {
+        let (queue, commands) = sync_channel(queue_size);
+        let default_sample_rate = client.default_sample_rate();
+
+        spawn(proc() { drain_loop(client, commands) });
+
+        BackgroundClient {
+            queue: queue,
+            dropped: Arc::new(AtomicUint::new(0)),
+            default_sample_rate: default_sample_rate
+        }
+    }
+
+    /// Number of metrics dropped because the queue was full or the
+    /// background task was gone, across all handles.
+    pub fn dropped(&self) -> uint {
+        self.dropped.load(SeqCst)
+    }
+
+    /// Flush and stop the background task, waiting until it is done. Metrics
+    /// sent afterwards through other handles are dropped.
+    pub fn shutdown(self) {
+        let (ack_send, ack_recv) = channel();
+
+        if self.queue.send_opt(Shutdown(ack_send)).is_ok() {
+            let _ = ack_recv.recv_opt();
+        }
+    }
+}
+
+
+impl MetricSink for BackgroundClient {
+    /// Queue `metric` for the background task, or drop it if the queue is
+    /// full.
+    fn send_metric(&mut self, metric: Metric) {
+        match self.queue.try_send(SendMetric(metric)) {
+            Ok(()) => {},
+            Err(_) => { self.dropped.fetch_add(1, SeqCst); }
+        }
+    }
+
+    /// Ask the background task to send any buffered metrics. Unlike sending
+    /// metrics, this waits for room in the queue.
+    fn flush(&mut self) {
+        let _ = self.queue.send_opt(FlushClient);
+    }
+
+    /// The default sample rate of the client in the background.
+    fn default_sample_rate(&self) -> f64 {
+        self.default_sample_rate
+    }
+}
+
+
+/// Run in the background task, sending metrics until told to stop or until
+/// every handle is gone. Dropping the client at the end flushes it.
+///
+/// While the client holds on to buffered or aggregated metrics, the task
+/// wakes up when they are due, so they are sent even if no more metrics come
+/// in.
+fn drain_loop(client: Client, commands: Receiver<Command>) {
+    let mut client = client;
+    let mut timer = Timer::new().ok();
+
+    loop {
+        let wakeup = match (timer.as_mut(), client.ms_until_due()) {
+            (Some(timer), Some(ms)) => Some(timer.oneshot(ms)),
+            _ => None
+        };
+
+        let command = match wakeup {
+            None => commands.recv_opt(),
+            Some(wakeup) => select! (
+                command = commands.recv_opt() => command,
+                () = wakeup.recv() => {
+                    client.flush_if_due();
+                    continue;
+                }
+            )
+        };
+
+        match command {
+            Some(SendMetric(metric)) => client.send_metric(metric),
+            Some(FlushClient) => client.flush(),
+            Some(Shutdown(ack)) => {
+                client.flush();
+                let _ = ack.send_opt(());
+                return;
+            },
+            None => return
+        }
+    }
+}
@@ -0,0 +1,262 @@
+//! Configuring a `Client` by name, or from the environment.
+
+use std::fmt;
+use std::from_str::FromStr;
+use std::io::IoError;
+use std::io::net::ip::SocketAddr;
+use std::os;
+
+use client::Client;
+use sampler::Sampler;
+use transport::{Transport, ResolvingUdpTransport, DEFAULT_RESOLVE_ERRORS, resolve_host};
+
+
+/// Host used when none is configured.
+pub static DEFAULT_HOST: &'static str = "localhost";
+
+/// Port used when none is configured.
+pub static DEFAULT_PORT: u16 = 8125;
+
+
+/// Reasons a `ClientBuilder` can fail to build a client.
+pub enum ConfigError {
+    BadPort(~str),
+    BadSampleRate(f64),
+    UnknownHost(~str),
+    SocketError(IoError),
+    /// `resolve_every` was asked for along with a protocol other than UDP.
+    ResolveNeedsUdp
+}
+
+
+impl fmt::Show for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            BadPort(ref port) => write!(f.buf, "bad port: '{}'", *port),
+            BadSampleRate(rate) => write!(f.buf, "bad sample rate: {} is not in (0, 1]", rate),
+            UnknownHost(ref host) => write!(f.buf, "unknown host: '{}'", *host),
+            SocketError(ref err) => write!(f.buf, "socket error: {}", *err),
+            ResolveNeedsUdp => write!(f.buf, "the host can only be resolved again with UDP")
+        }
+    }
+}
+
+
+/// How the built client gets its metrics to the server.
+enum Protocol {
+    Udp,
+    Tcp,
+    Unix(Path),
+    Custom(~Transport)
+}
+
+
+/// Builds a `Client` from a host name and port, along with the rest of its
+/// configuration.
+pub struct ClientBuilder {
+    host: ~str,
+    port: u16,
+    protocol: Protocol,
+    resolve_interval_ms: Option<u64>,
+
+    prefix: Option<~str>,
+    tags: ~[(~str, ~str)],
+
+    sampler: Option<~Sampler>,
+    sample_rate: f64,
+
+    payload_size: uint, // 0 when unbuffered
+    max_latency_ms: u64,
+    aggregate: Option<(u64, uint)>
+}
+
+
+impl ClientBuilder {
+    /// Start with a client sending to `DEFAULT_HOST`:`DEFAULT_PORT` over UDP.
+    pub fn new() -> ClientBuilder {
+        ClientBuilder {
+            host: DEFAULT_HOST.to_owned(),
+            port: DEFAULT_PORT,
+            protocol: Udp,
+            resolve_interval_ms: None,
+
+            prefix: None,
+            tags: ~[],
+
+            sampler: None,
+            sample_rate: 1.0,
+
+            payload_size: 0,
+            max_latency_ms: 0,
+            aggregate: None
+        }
+    }
+
+    /// Start with the `STATSD_HOST`, `STATSD_PORT` and `STATSD_PREFIX`
+    /// environment variables, using the defaults for any that aren't set.
+    pub fn from_env() -> Result<ClientBuilder, ConfigError> {
+        ClientBuilder::from_vars(|name| os::getenv(name))
+    }
+
+    /// Like `from_env`, but looking the variables up with `getenv`.
+    pub fn from_vars(getenv: |&str| -> Option<~str>) -> Result<ClientBuilder, ConfigError> {
+        let mut builder = ClientBuilder::new();
+
+        for host in getenv("STATSD_HOST").move_iter() {
+            builder = builder.host(host.as_slice());
+        }
+
+        for port in getenv("STATSD_PORT").move_iter() {
+            match FromStr::from_str(port.as_slice()) {
+                Some(port) => builder = builder.port(port),
+                None => return Err(BadPort(port))
+            }
+        }
+
+        for prefix in getenv("STATSD_PREFIX").move_iter() {
+            builder = builder.prefix(prefix.as_slice());
+        }
+
+        Ok(builder)
+    }
+
+    /// Send to `host`, a host name or IP address resolved when the client is
+    /// built.
+    pub fn host(mut self, host: &str) -> ClientBuilder {
+        self.host = host.to_owned();
+        self
+    }
+
+    pub fn port(mut self, port: u16) -> ClientBuilder {
+        self.port = port;
+        self
+    }
+
+    /// Resolve the host again every `interval_ms`, and whenever sends keep
+    /// failing, instead of only once. Only possible with UDP.
+    pub fn resolve_every(mut self, interval_ms: u64) -> ClientBuilder {
+        self.resolve_interval_ms = Some(interval_ms);
+        self
+    }
+
+    /// Send newline delimited metrics over TCP instead of UDP.
+    pub fn tcp(mut self) -> ClientBuilder {
+        self.protocol = Tcp;
+        self
+    }
+
+    /// Send to the Unix datagram socket at `path` instead of the host and
+    /// port.
+    pub fn unix(mut self, path: &Path) -> ClientBuilder {
+        self.protocol = Unix(path.clone());
+        self
+    }
+
+    /// Send through `transport` instead of the host and port.
+    pub fn transport(mut self, transport: ~Transport) -> ClientBuilder {
+        self.protocol = Custom(transport);
+        self
+    }
+
+    /// Prepend `prefix` and a `.` to every key. An empty prefix is ignored.
+    pub fn prefix(mut self, prefix: &str) -> ClientBuilder {
+        self.prefix = if prefix.is_empty() { None } else { Some(prefix.to_owned()) };
+        self
+    }
+
+    /// Send the `key:value` tag along with every metric.
+    pub fn default_tag(mut self, key: &str, value: &str) -> ClientBuilder {
+        self.tags.push((key.to_owned(), value.to_owned()));
+        self
+    }
+
+    pub fn sampler(mut self, sampler: ~Sampler) -> ClientBuilder {
+        self.sampler = Some(sampler);
+        self
+    }
+
+    /// Sample metrics sent without a sample rate of their own at
+    /// `sample_rate`. See `Client::set_default_sample_rate`.
+    pub fn sample_rate(mut self, sample_rate: f64) -> ClientBuilder {
+        self.sample_rate = sample_rate;
+        self
+    }
+
+    /// Pack metrics into packets. See `Client::new_buffered`.
+    pub fn buffered(mut self, payload_size: uint, max_latency_ms: u64) -> ClientBuilder {
+        self.payload_size = payload_size;
+        self.max_latency_ms = max_latency_ms;
+        self
+    }
+
+    /// Aggregate metrics before sending them. See `Client::new_aggregated`.
+    pub fn aggregated(mut self, interval_ms: u64, max_samples: uint) -> ClientBuilder {
+        self.aggregate = Some((interval_ms, max_samples));
+        self
+    }
+
+    /// Resolve the host and create the client.
+    pub fn build(self) -> Result<Client, ConfigError> {
+        let ClientBuilder {
+            host, port, protocol, resolve_interval_ms, prefix, tags, sampler,
+            sample_rate, payload_size, max_latency_ms, aggregate
+        } = self;
+
+        if !(sample_rate > 0.0 && sample_rate <= 1.0) {
+            return Err(BadSampleRate(sample_rate));
+        }
+
+        let mut client = match (protocol, resolve_interval_ms) {
+            (Udp, None) => match Client::new(try!(resolve(host.as_slice(), port))) {
+                Ok(client) => client,
+                Err(err) => return Err(SocketError(err))
+            },
+            (Udp, Some(interval_ms)) => {
+                let dest = try!(resolve(host.as_slice(), port));
+
+                match ResolvingUdpTransport::new_resolved(host.as_slice(), dest, interval_ms,
+                                                          DEFAULT_RESOLVE_ERRORS) {
+                    Ok(transport) => Client::new_with_transport(box transport as ~Transport),
+                    Err(err) => return Err(SocketError(err))
+                }
+            },
+            (_, Some(_)) => return Err(ResolveNeedsUdp),
+            (Tcp, None) => Client::new_tcp(try!(resolve(host.as_slice(), port))),
+            (Unix(path), None) => match Client::new_unix(&path) {
+                Ok(client) => client,
+                Err(err) => return Err(SocketError(err))
+            },
+            (Custom(transport), None) => Client::new_with_transport(transport)
+        };
+
+        for prefix in prefix.iter() {
+            client.set_prefix(prefix.as_slice());
+        }
+
+        for &(ref key, ref value) in tags.iter() {
+            client.add_default_tag(key.as_slice(), value.as_slice());
+        }
+
+        for sampler in sampler.move_iter() {
+            client.set_sampler(sampler);
+        }
+
+        client.set_default_sample_rate(sample_rate);
+        client.set_buffered(payload_size, max_latency_ms);
+
+        for &(interval_ms, max_samples) in aggregate.iter() {
+            client.set_aggregated(interval_ms, max_samples);
+        }
+
+        Ok(client)
+    }
+}
+
+
+/// Look up `host`, reporting failure as a `ConfigError`.
+fn resolve(host: &str, port: u16) -> Result<SocketAddr, ConfigError> {
+    match resolve_host(host) {
+        Ok(ip) => Ok(SocketAddr { ip: ip, port: port }),
+        Err(_) => Err(UnknownHost(host.to_owned()))
+    }
+}
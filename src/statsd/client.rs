@@ -1,103 +1,366 @@
-use std::from_str::FromStr;
+use std::cmp;
+use std::io::{IoError, IoResult};
 use std::io::net::ip::SocketAddr;
-use std::io::net::udp::UdpSocket;
-use rand::random;
+use std::mem;
+
+use aggregate::Aggregator;
+use metric;
+use metric::Metric;
+use sampler::{Sampler, RandomSampler};
+use sink::MetricSink;
+use transport::{Transport, UdpTransport, TcpTransport, UnixTransport};
+use transport::{ResolvingUdpTransport, DEFAULT_RESOLVE_ERRORS};
 
 use time;
 
 
+/// Largest payload that can safely be sent over the internet.
+pub static PAYLOAD_INTERNET: uint = 512;
+
+/// Largest payload that fits in a standard ethernet frame.
+pub static PAYLOAD_ETHERNET: uint = 1432;
+
+/// Largest payload that fits in a jumbo ethernet frame.
+pub static PAYLOAD_JUMBO: uint = 8932;
+
+
 /** Simple interface to a statsd host.
 
 Does only minimal computation (basically just whether or not to send sampled
 data and timing a function call). Most work is handled by the server.
 
-**TODO**: allow prefixing keys.
+Metrics are sent over UDP by default, but any `Transport` can be used instead.
+The methods for sending metrics come from the `MetricSink` trait.
+
+A client can be given a prefix and a set of default tags, which are applied to
+every metric it sends.
+
+A buffered client packs as many newline separated metrics as fit into a single
+packet, sending it when the next metric wouldn't fit, when `flush` is called,
+when a metric is sent or `flush_if_due` is called after the buffer has been
+waiting for longer than the configured latency, or when the client is dropped.
+
+An aggregating client combines metrics before sending them (see
+`Aggregator`), sending the aggregated lines when a metric is sent or
+`flush_if_due` is called after the aggregation interval has passed, when
+`flush` is called, or when the client is dropped. It can be buffered as well,
+to pack those lines together.
+
+The client has no timer of its own, so nothing is sent while no metrics are
+sent unless `flush_if_due` is called. A `BackgroundClient` calls it when
+`ms_until_due` says so.
+
+Sending metrics never fails loudly, since statsd is meant to be fire and
+forget. Instead, the client counts failed sends and keeps the last error, so
+that they can be checked (or exported) by the caller.
 */
 pub struct Client {
-    dest: SocketAddr,
-    sock: UdpSocket
+    transport: ~Transport,
+    prefix: ~str,
+    tags: ~[(~str, ~str)],
+    sampler: ~Sampler,
+    default_sample_rate: f64,
+    aggregator: Option<Aggregator>,
+
+    buffer: ~str,
+    max_payload: uint, // 0 when unbuffered
+    max_latency_ns: u64,
+    buffered_since_ns: u64,
+
+    send_errors: uint,
+    last_error: Option<IoError>
 }
 
 
 impl Client {
-    /// Construct a new statsd client given a hostname and port.
-    pub fn new(dest: SocketAddr) -> Client {
-        // XXX: Is this the right way to do this?
-        let client_addr: SocketAddr = FromStr::from_str("0.0.0.0:0").unwrap();
-        let sock = UdpSocket::bind(client_addr).unwrap();
+    /// Construct a new statsd client given a hostname and port. Fails if a
+    /// local socket can't be bound.
+    pub fn new(dest: SocketAddr) -> IoResult<Client> {
+        let transport = try!(UdpTransport::new(dest));
 
-        Client { dest: dest, sock: sock }
+        Ok(Client::new_with_transport(box transport as ~Transport))
     }
 
-    /// Increment the given `name` by one with a probability of `sample_rate`.
-    pub fn incr(&mut self, name: &str, sample_rate: f64) {
-        self.count_sampled(name, 1.0, sample_rate);
+    /// Construct a client sending metrics over UDP to `host`, which is
+    /// resolved again every `interval_ms` and whenever sends keep failing.
+    /// Fails if `host` can't be resolved or a local socket can't be bound.
+    pub fn new_resolving(host: &str, port: u16, interval_ms: u64) -> IoResult<Client> {
+        let transport = try!(ResolvingUdpTransport::new(host, port, interval_ms,
+                                                        DEFAULT_RESOLVE_ERRORS));
+
+        Ok(Client::new_with_transport(box transport as ~Transport))
     }
 
-    /// Decrement the given `name` by one with a probability of `sample_rate`.
-    pub fn decr(&mut self, name: &str, sample_rate: f64) {
-        self.count_sampled(name, -1.0, sample_rate);
+    /// Construct a client sending metrics to `dest` over TCP.
+    pub fn new_tcp(dest: SocketAddr) -> Client {
+        Client::new_with_transport(box TcpTransport::new(dest) as ~Transport)
     }
 
-    /// Add `value` to the given `name`.
-    pub fn count(&mut self, name: &str, value: f64) {
-        self.count_sampled(name, value, 1.0);
+    /// Construct a client sending metrics to the Unix datagram socket bound
+    /// at `path`.
+    pub fn new_unix(path: &Path) -> IoResult<Client> {
+        let transport = try!(UnixTransport::new(path));
+
+        Ok(Client::new_with_transport(box transport as ~Transport))
     }
 
-    /// Add `value` to the given `name` with a probability of `sample_rate`.
-    pub fn count_sampled(&mut self, name: &str, value: f64, sample_rate: f64) {
-        let data = format!("{}:{}|c@{}", name, value, sample_rate);
-        self.send_sampled(data, sample_rate);
+    /// Construct a client sending metrics through `transport`.
+    pub fn new_with_transport(transport: ~Transport) -> Client {
+        Client {
+            transport: transport,
+            prefix: ~"",
+            tags: ~[],
+            sampler: box RandomSampler::new() as ~Sampler,
+            default_sample_rate: 1.0,
+            aggregator: None,
+
+            buffer: ~"",
+            max_payload: 0,
+            max_latency_ns: 0,
+            buffered_since_ns: 0,
+
+            send_errors: 0,
+            last_error: None
+        }
     }
 
-    /// Simply set the given `name` to `value`.
-    pub fn gauge(&mut self, name: &str, value: f64) {
-        let data = format!("{}:{}|g", name, value);
-        self.send(data);
+    /// Create a buffered client, sending packets of up to `payload_size`
+    /// bytes, and holding on to metrics for no longer than `max_latency_ms`.
+    pub fn new_buffered(dest: SocketAddr, payload_size: uint,
+                        max_latency_ms: u64) -> IoResult<Client> {
+        let mut client = try!(Client::new(dest));
+        client.set_buffered(payload_size, max_latency_ms);
+
+        Ok(client)
     }
 
-    /** Specify that this instance of `name` took `ms` milliseconds.
+    /// Create an aggregating client, sending what it aggregated every
+    /// `interval_ms` and keeping up to `max_samples` samples of each timer,
+    /// histogram, distribution or set in between.
+    pub fn new_aggregated(dest: SocketAddr, interval_ms: u64,
+                          max_samples: uint) -> IoResult<Client> {
+        let mut client = try!(Client::new(dest));
+        client.set_aggregated(interval_ms, max_samples);
+
+        Ok(client)
+    }
+
+    /// Create with a prefix that will be automatically prepended to all keys.
+    /// An empty prefix is ignored.
+    pub fn new_with_prefix(prefix: &str, dest: SocketAddr) -> IoResult<Client> {
+        let mut client = try!(Client::new(dest));
+        client.set_prefix(prefix);
+
+        Ok(client)
+    }
 
-    It doesn't matter what you use here. Statsd bizarrely treats timed values
-    specially. A better name for this kind of value would be `histogram`,
-    because that's what's really being calculated from the server side. Some
-    server implementations (such as the one included here) support histogram
-    keys.
-    */
-    pub fn time(&mut self, name: &str, ms: uint) {
-        let data = format!("{}:{}|ms", name, ms);
-        self.send(data);
+    /// Number of packets that failed to send.
+    pub fn send_errors(&self) -> uint {
+        self.send_errors
     }
 
-    /// Similar to `time`, but the `ms` value sent is the amount of time taken
-    /// to execute the proc.
-    pub fn time_block(&mut self, name: &str, block: proc()) {
-        let start_time = time::precise_time_ns();
-        block();
-        let run_time_ms = (time::precise_time_ns() - start_time) / 1000;
+    /// Take the most recent send error, if there has been one since the last
+    /// call.
+    pub fn take_last_error(&mut self) -> Option<IoError> {
+        self.last_error.take()
+    }
+
+    /// Send buffered or aggregated metrics that have been held for longer
+    /// than the configured latency or aggregation interval.
+    pub fn flush_if_due(&mut self) {
+        self.write_aggregated(false);
+
+        if !self.buffer.is_empty() &&
+            time::precise_time_ns() - self.buffered_since_ns >= self.max_latency_ns {
+            self.flush_buffer();
+        }
+    }
+
+    /// Milliseconds until buffered or aggregated metrics are due to be sent,
+    /// or `None` if nothing is waiting.
+    pub fn ms_until_due(&self) -> Option<u64> {
+        let mut due_ns = None;
+
+        if !self.buffer.is_empty() {
+            let waited_ns = time::precise_time_ns() - self.buffered_since_ns;
+            due_ns = Some(self.max_latency_ns - cmp::min(waited_ns, self.max_latency_ns));
+        }
+
+        for aggregator in self.aggregator.iter() {
+            if !aggregator.is_empty() {
+                let ns = aggregator.ns_until_due();
+                due_ns = Some(due_ns.map_or(ns, |due| cmp::min(due, ns)));
+            }
+        }
+
+        // Round up, so that it is really due by then.
+        due_ns.map(|ns| (ns + 999999) / 1000000)
+    }
+
+    /// Switch to buffered mode. See `new_buffered`.
+    pub fn set_buffered(&mut self, payload_size: uint, max_latency_ms: u64) {
+        self.flush();
+
+        self.max_payload = payload_size;
+        self.max_latency_ns = max_latency_ms * 1000000;
+    }
+
+    /// Switch to aggregating metrics. See `new_aggregated`.
+    pub fn set_aggregated(&mut self, interval_ms: u64, max_samples: uint) {
+        self.flush();
+
+        self.aggregator = Some(Aggregator::new(interval_ms, max_samples));
+    }
+
+    /// Number of timer, histogram, distribution or set samples dropped
+    /// because too many were aggregated.
+    pub fn dropped_samples(&self) -> uint {
+        self.aggregator.as_ref().map_or(0, |aggregator| aggregator.dropped_samples())
+    }
+
+    /// Decide which samples of sampled metrics are sent with `sampler`
+    /// instead of at random.
+    pub fn set_sampler(&mut self, sampler: ~Sampler) {
+        self.sampler = sampler;
+    }
 
-        self.time(name, (run_time_ms as uint));
+    /// Prepend `prefix` and a `.` to every key sent from now on. An empty
+    /// prefix is ignored.
+    pub fn set_prefix(&mut self, prefix: &str) {
+        self.prefix = metric::key_prefix(prefix);
     }
 
-    /// Append `val` to the vector `name`. Server will generate summary
-    /// statistics for the vector on each flush.
-    pub fn hist(&mut self, name: &str, val: f64) {
-        let data = format!("{}:{}|h", name, val);
-        self.send(data);
+    /// Sample the metrics sent without a sample rate of their own (through
+    /// `count`, `time`, `time_block` and `hist`) at `sample_rate`. Metrics
+    /// given a rate, even a rate of 1, are sampled as asked.
+    pub fn set_default_sample_rate(&mut self, sample_rate: f64) {
+        self.default_sample_rate = sample_rate;
     }
 
-    /// Data goes in, data comes out.
+    /// Add a tag that will be sent along with every metric.
+    pub fn add_default_tag(&mut self, key: &str, value: &str) {
+        self.tags.push((key.to_owned(), value.to_owned()));
+    }
+
+    /// Apply the prefix and default tags to `metric`, then send it.
+    fn write_metric(&mut self, metric: Metric) {
+        let mut metric = metric;
+
+        if !self.prefix.is_empty() {
+            metric.name = format!("{}{}", self.prefix, metric.name);
+        }
+
+        metric.tags.push_all(self.tags.as_slice());
+
+        self.send(metric.encode());
+    }
+
+    /// Send `data` right away, or add it to the buffer in buffered mode.
     fn send(&mut self, data: &str) {
-        // TODO: Currently just ignoring send errors, should other behavior be
-        //       used?
-        let _ = self.sock.sendto(data.as_bytes(), self.dest);
+        if self.max_payload == 0 {
+            return self.send_packet(data);
+        }
+
+        // Make room first if the line doesn't fit after what's buffered.
+        if !self.buffer.is_empty() && self.buffer.len() + 1 + data.len() > self.max_payload {
+            self.flush_buffer();
+        }
+
+        if self.buffer.is_empty() {
+            self.buffered_since_ns = time::precise_time_ns();
+        } else {
+            self.buffer.push_char('\n');
+        }
+
+        self.buffer.push_str(data);
+
+        let waited_ns = time::precise_time_ns() - self.buffered_since_ns;
+        if self.buffer.len() >= self.max_payload || waited_ns >= self.max_latency_ns {
+            self.flush_buffer();
+        }
+    }
+
+    /// Send what is buffered as a single packet.
+    fn flush_buffer(&mut self) {
+        if !self.buffer.is_empty() {
+            let buffer = mem::replace(&mut self.buffer, ~"");
+            self.send_packet(buffer);
+        }
+    }
+
+    /// Data goes in, data comes out. Errors are recorded rather than
+    /// returned.
+    fn send_packet(&mut self, data: &str) {
+        match self.transport.send(data.as_bytes()) {
+            Ok(()) => {},
+            Err(err) => {
+                self.send_errors += 1;
+                self.last_error = Some(err);
+            }
+        }
     }
 
     /// Data goes in, data comes out. With a defined probability.
-    fn send_sampled(&mut self, data: &str, sample_rate: f64) {
-        if random::<f64>() > sample_rate || sample_rate >= 1.0 {
-            self.send(data);
+    fn send_sampled(&mut self, metric: Metric, sample_rate: f64) {
+        if !self.sampler.sample(sample_rate) {
+            return;
         }
+
+        let unaggregated = match self.aggregator {
+            Some(ref mut aggregator) => aggregator.add(metric),
+            None => Some(metric)
+        };
+
+        for metric in unaggregated.move_iter() {
+            self.write_metric(metric);
+        }
+
+        self.write_aggregated(false);
+    }
+
+    /// Write out the aggregated metrics, if there are any and the interval
+    /// has passed (or `force` is set).
+    fn write_aggregated(&mut self, force: bool) {
+        let metrics = match self.aggregator {
+            Some(ref mut aggregator) => {
+                if !force && !aggregator.is_due() {
+                    return;
+                }
+
+                aggregator.drain()
+            },
+            None => return
+        };
+
+        for metric in metrics.move_iter() {
+            self.write_metric(metric);
+        }
+    }
+
+}
+
+
+impl MetricSink for Client {
+    fn send_metric(&mut self, metric: Metric) {
+        let sample_rate = metric.kind.sample_rate();
+        self.send_sampled(metric, sample_rate);
     }
 
-}
\ No newline at end of file
+    fn default_sample_rate(&self) -> f64 {
+        self.default_sample_rate
+    }
+
+    /// Send any aggregated or buffered metrics immediately.
+    fn flush(&mut self) {
+        self.write_aggregated(true);
+        self.flush_buffer();
+    }
+}
+
+
+impl Drop for Client {
+    /// Don't lose anything still buffered.
+    fn drop(&mut self) {
+        self.flush();
+    }
+}
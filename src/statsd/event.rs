@@ -0,0 +1,245 @@
+//! DogStatsD events and service checks.
+//!
+//! These are sent over the same socket as metrics, but describe something
+//! that happened rather than a value to aggregate.
+
+use metric;
+use metric::ParseError;
+
+use std::fmt;
+use std::from_str::FromStr;
+use std::num::CheckedAdd;
+use std::option::{Option, Some, None};
+use std::result::{Result, Ok, Err};
+
+
+/// Status reported by a service check.
+#[deriving(Eq, Clone)]
+pub enum CheckStatus {
+    StatusOk,
+    StatusWarning,
+    StatusCritical,
+    StatusUnknown
+}
+
+
+impl fmt::Show for CheckStatus {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            StatusOk       => write!(f.buf, "OK"),
+            StatusWarning  => write!(f.buf, "WARNING"),
+            StatusCritical => write!(f.buf, "CRITICAL"),
+            StatusUnknown  => write!(f.buf, "UNKNOWN")
+        }
+    }
+}
+
+
+/// An event, sent as
+/// `_e{<title_len>,<text_len>}:<title>|<text>|d:<ts>|h:<host>|k:<key>|p:<priority>|s:<source>|t:<alert_type>|#<tags>`
+///
+/// Everything after the text is optional.
+#[deriving(Eq, Clone, Show)]
+pub struct Event {
+    pub title: ~str,
+    pub text: ~str,
+    pub timestamp: Option<i64>,
+    pub hostname: Option<~str>,
+    pub aggregation_key: Option<~str>,
+    pub priority: Option<~str>,
+    pub source_type: Option<~str>,
+    pub alert_type: Option<~str>,
+    pub tags: ~[(~str, ~str)]
+}
+
+
+/// A service check, sent as
+/// `_sc|<name>|<status>|d:<ts>|h:<host>|#<tags>|m:<message>`
+///
+/// Everything after the status is optional, but the message must come last.
+#[deriving(Eq, Clone, Show)]
+pub struct ServiceCheck {
+    pub name: ~str,
+    pub status: CheckStatus,
+    pub timestamp: Option<i64>,
+    pub hostname: Option<~str>,
+    pub message: Option<~str>,
+    pub tags: ~[(~str, ~str)]
+}
+
+
+fn owned_tags(section: &str) -> Option<~[(~str, ~str)]> {
+    metric::parse_tags(section).ok().map(|tags| {
+        tags.iter()
+            .map(|&(key, value)| (key.to_owned(), value.to_owned()))
+            .collect()
+    })
+}
+
+
+impl Event {
+    pub fn parse(line: &str) -> Result<Event, ParseError> {
+        match Event::parse_opt(line) {
+            Some(event) => Ok(event),
+            None => Err(metric::BadEvent)
+        }
+    }
+
+    fn parse_opt(line: &str) -> Option<Event> {
+        if !line.starts_with("_e{") {
+            return None;
+        }
+
+        let header_end = match line.find('}') {
+            Some(pos) => pos,
+            None => return None
+        };
+
+        let mut lengths = line.slice(3, header_end).split(',')
+            .map(|len| from_str::<uint>(len));
+
+        let (title_len, text_len) = match (lengths.next(), lengths.next(), lengths.next()) {
+            (Some(Some(title)), Some(Some(text)), None) => (title, text),
+            _ => return None
+        };
+
+        // Lengths are given in bytes, and are followed by `:<title>|<text>`.
+        // Lengths that overflow can't fit in the line either.
+        let title_start = header_end + 2;
+        let text_start = match title_start.checked_add(&title_len).and_then(|end| end.checked_add(&1)) {
+            Some(pos) => pos,
+            None => return None
+        };
+        let text_end = match text_start.checked_add(&text_len) {
+            Some(pos) => pos,
+            None => return None
+        };
+
+        if text_end > line.len() ||
+            !line.slice_from(header_end).starts_with("}:") ||
+            !line.is_char_boundary(text_start - 1) ||
+            !line.is_char_boundary(text_end) ||
+            line.char_at(text_start - 1) != '|' ||
+            title_len == 0 {
+            return None;
+        }
+
+        let mut event = Event {
+            title: line.slice(title_start, text_start - 1).to_owned(),
+            // Newlines can't be sent as is, since they separate lines.
+            text: line.slice(text_start, text_end).replace("\\n", "\n"),
+            timestamp: None,
+            hostname: None,
+            aggregation_key: None,
+            priority: None,
+            source_type: None,
+            alert_type: None,
+            tags: ~[]
+        };
+
+        let rest = line.slice_from(text_end);
+        if rest.is_empty() {
+            return Some(event);
+        } else if !rest.starts_with("|") {
+            return None;
+        }
+
+        for section in rest.slice_from(1).split('|') {
+            if section.starts_with("#") {
+                event.tags = match owned_tags(section.slice_from(1)) {
+                    Some(tags) => tags,
+                    None => return None
+                };
+                continue;
+            }
+
+            if section.len() < 2 || section.char_at(1) != ':' {
+                return None;
+            }
+
+            let value = section.slice_from(2);
+
+            match section.char_at(0) {
+                'd' => event.timestamp = match FromStr::from_str(value) {
+                    Some(ts) => Some(ts),
+                    None => return None
+                },
+                'h' => event.hostname = Some(value.to_owned()),
+                'k' => event.aggregation_key = Some(value.to_owned()),
+                'p' => event.priority = Some(value.to_owned()),
+                's' => event.source_type = Some(value.to_owned()),
+                't' => event.alert_type = Some(value.to_owned()),
+                _ => return None
+            }
+        }
+
+        Some(event)
+    }
+}
+
+
+impl ServiceCheck {
+    pub fn parse(line: &str) -> Result<ServiceCheck, ParseError> {
+        match ServiceCheck::parse_opt(line) {
+            Some(check) => Ok(check),
+            None => Err(metric::BadServiceCheck)
+        }
+    }
+
+    fn parse_opt(line: &str) -> Option<ServiceCheck> {
+        if !line.starts_with("_sc|") {
+            return None;
+        }
+
+        // The message is free text that may contain `|`, so it's split off
+        // before looking at the other sections.
+        let (line, message) = match line.find_str("|m:") {
+            Some(pos) => (line.slice_to(pos), Some(line.slice_from(pos + 3).to_owned())),
+            None => (line, None)
+        };
+
+        let mut sections = line.slice_from(4).split('|');
+
+        let name = match sections.next() {
+            Some("") | None => return None,
+            Some(name) => name.to_owned()
+        };
+
+        let status = match sections.next() {
+            Some("0") => StatusOk,
+            Some("1") => StatusWarning,
+            Some("2") => StatusCritical,
+            Some("3") => StatusUnknown,
+            _ => return None
+        };
+
+        let mut check = ServiceCheck {
+            name: name,
+            status: status,
+            timestamp: None,
+            hostname: None,
+            message: message,
+            tags: ~[]
+        };
+
+        for section in sections {
+            if section.starts_with("#") {
+                check.tags = match owned_tags(section.slice_from(1)) {
+                    Some(tags) => tags,
+                    None => return None
+                };
+            } else if section.starts_with("d:") {
+                check.timestamp = match FromStr::from_str(section.slice_from(2)) {
+                    Some(ts) => Some(ts),
+                    None => return None
+                };
+            } else if section.starts_with("h:") {
+                check.hostname = Some(section.slice_from(2).to_owned());
+            } else {
+                return None;
+            }
+        }
+
+        Some(check)
+    }
+}
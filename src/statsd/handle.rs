@@ -0,0 +1,268 @@
+//! Handles to metrics registered up front, for sending from hot paths.
+
+use sync::Arc;
+
+use metric;
+use metric::{Metric, MetricKind};
+use sampler::Sampler;
+use shared::SharedClient;
+
+
+/** Creates handles to single metrics of a `SharedClient`.
+
+Each handle has its metric's prefixed key, type and tags encoded when it is
+created, so sending a value only writes that value into the handle's own
+buffer. No strings are allocated unless the value is too large to fit in a
+64 bit integer.
+
+Handles can be cloned, and sent to other tasks. Each handle, and each clone,
+decides which samples to send with a sampler of its own, made by the client's
+`new_sampler`.
+*/
+#[deriving(Clone)]
+pub struct Registry {
+    client: Arc<SharedClient>
+}
+
+
+impl Registry {
+    pub fn new(client: SharedClient) -> Registry {
+        Registry::new_shared(Arc::new(client))
+    }
+
+    /// Create handles sending through a client that is already shared.
+    pub fn new_shared(client: Arc<SharedClient>) -> Registry {
+        Registry { client: client }
+    }
+
+    /// The client the handles send through.
+    pub fn client<'a>(&'a self) -> &'a SharedClient {
+        &*self.client
+    }
+
+    pub fn counter(&self, name: &str) -> CounterHandle {
+        self.counter_sampled(name, 1.0)
+    }
+
+    /// A counter sent with a probability of `sample_rate`.
+    pub fn counter_sampled(&self, name: &str, sample_rate: f64) -> CounterHandle {
+        CounterHandle { line: self.line(metric::Counter(sample_rate), name) }
+    }
+
+    pub fn gauge(&self, name: &str) -> GaugeHandle {
+        GaugeHandle { line: self.line(metric::Gauge, name) }
+    }
+
+    pub fn timer(&self, name: &str) -> TimerHandle {
+        TimerHandle { line: self.line(metric::Timer(1.0), name) }
+    }
+
+    pub fn histogram(&self, name: &str) -> HistogramHandle {
+        HistogramHandle { line: self.line(metric::Histogram(1.0), name) }
+    }
+
+    fn line(&self, kind: MetricKind, name: &str) -> EncodedLine {
+        let name = format!("{}{}", self.client.prefix(), name);
+        let mut metric = Metric::new(kind, name.as_slice(), 0.0);
+        metric.tags.push_all(self.client.default_tags());
+
+        let head = format!("{}:", metric.encode_name()).into_bytes();
+        let tail = format!("|{}{}", kind.type_str(), metric.encode_suffix()).into_bytes();
+
+        EncodedLine {
+            client: self.client.clone(),
+            buffer: head.clone(),
+            head: head,
+            tail: tail,
+            sample_rate: kind.sample_rate(),
+            sampler: self.client.new_sampler()
+        }
+    }
+}
+
+
+/// A metric line encoded up to its value.
+struct EncodedLine {
+    client: Arc<SharedClient>,
+    buffer: ~[u8],
+    head: ~[u8], // key and `:`
+    tail: ~[u8], // type, sample rate and tags
+    sample_rate: f64,
+    sampler: ~Sampler
+}
+
+
+impl Clone for EncodedLine {
+    /// The clone gets a sampler of its own.
+    fn clone(&self) -> EncodedLine {
+        EncodedLine {
+            client: self.client.clone(),
+            buffer: self.buffer.clone(),
+            head: self.head.clone(),
+            tail: self.tail.clone(),
+            sample_rate: self.sample_rate,
+            sampler: self.client.new_sampler()
+        }
+    }
+}
+
+
+impl EncodedLine {
+    /// Send `value`, with `sign` in front of it. A negative absolute gauge is
+    /// sent after resetting the gauge to 0, as in `Metric::encode`.
+    fn send(&mut self, value: f64, sign: &str, reset: bool) {
+        if !self.sampler.sample(self.sample_rate) {
+            return;
+        }
+
+        self.buffer.truncate(0);
+
+        if reset {
+            self.buffer.push_all(self.head);
+            self.buffer.push('0' as u8);
+            self.buffer.push_all(self.tail);
+            self.buffer.push('\n' as u8);
+        }
+
+        self.buffer.push_all(self.head);
+        self.buffer.push_all(sign.as_bytes());
+        push_value(&mut self.buffer, value);
+        self.buffer.push_all(self.tail);
+
+        self.client.send_packet(self.buffer.as_slice());
+    }
+}
+
+
+/// Append `value` to `buf` the way `Metric::encode` formats it, with at most
+/// six decimal places. Only values too large to fit in a `u64` are formatted
+/// into a string first.
+fn push_value(buf: &mut ~[u8], value: f64) {
+    let abs = value.abs();
+
+    // Also catches NaN.
+    if !(abs < 1e19) {
+        buf.push_all(format!("{}", value).as_bytes());
+        return;
+    }
+
+    let mut whole = abs.trunc() as u64;
+    let mut fraction = ((abs - abs.trunc()) * 1e6).round() as u64;
+
+    // The fraction rounded up to the next whole number.
+    if fraction == 1000000 {
+        whole += 1;
+        fraction = 0;
+    }
+
+    if value < 0.0 {
+        buf.push('-' as u8);
+    }
+
+    push_digits(buf, whole, 1);
+
+    if fraction > 0 {
+        // Trailing zeros aren't written.
+        let mut width = 6;
+        while fraction % 10 == 0 {
+            fraction /= 10;
+            width -= 1;
+        }
+
+        buf.push('.' as u8);
+        push_digits(buf, fraction, width);
+    }
+}
+
+
+/// Append the digits of `n` to `buf`, padded with zeros to at least `width`
+/// digits.
+fn push_digits(buf: &mut ~[u8], n: u64, width: uint) {
+    let mut digits = [0u8, ..20];
+    let mut start = digits.len();
+    let mut n = n;
+
+    loop {
+        start -= 1;
+        digits[start] = '0' as u8 + (n % 10) as u8;
+        n /= 10;
+
+        if n == 0 && digits.len() - start >= width {
+            break;
+        }
+    }
+
+    buf.push_all(digits.slice_from(start));
+}
+
+
+#[deriving(Clone)]
+pub struct CounterHandle {
+    line: EncodedLine
+}
+
+
+impl CounterHandle {
+    /// Increment the counter by one.
+    pub fn incr(&mut self) {
+        self.count(1.0);
+    }
+
+    /// Add `value` to the counter.
+    pub fn count(&mut self, value: f64) {
+        self.line.send(value, "", false);
+    }
+}
+
+
+#[deriving(Clone)]
+pub struct GaugeHandle {
+    line: EncodedLine
+}
+
+
+impl GaugeHandle {
+    /// Set the gauge to `value`.
+    pub fn set(&mut self, value: f64) {
+        self.line.send(value, "", value < 0.0);
+    }
+
+    /// Add `value` to the current value of the gauge.
+    pub fn incr(&mut self, value: f64) {
+        let sign = if value < 0.0 { "" } else { "+" };
+        self.line.send(value, sign, false);
+    }
+
+    /// Subtract `value` from the current value of the gauge.
+    pub fn decr(&mut self, value: f64) {
+        self.incr(-value);
+    }
+}
+
+
+#[deriving(Clone)]
+pub struct TimerHandle {
+    line: EncodedLine
+}
+
+
+impl TimerHandle {
+    /// Record a run of the timer taking `ms` milliseconds.
+    pub fn record(&mut self, ms: uint) {
+        self.line.send(ms as f64, "", false);
+    }
+}
+
+
+#[deriving(Clone)]
+pub struct HistogramHandle {
+    line: EncodedLine
+}
+
+
+impl HistogramHandle {
+    /// Add `value` to the histogram.
+    pub fn record(&mut self, value: f64) {
+        self.line.send(value, "", false);
+    }
+}
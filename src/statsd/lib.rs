@@ -17,11 +17,37 @@ extern crate test;
 extern crate time;
 extern crate collections;
 extern crate rand;
+extern crate libc;
+extern crate sync;
 
 pub mod metric;
 
+pub mod event;
+
+pub mod sink;
+
+pub mod sampler;
+
+pub mod aggregate;
+
 pub mod client;
 
+pub mod builder;
+
+pub mod background;
+
+pub mod shared;
+
+pub mod handle;
+
+pub mod transport;
+
+pub mod unix;
+
+pub mod udp;
+
+pub mod tcp;
+
 pub mod server {
     pub mod backend;
     pub mod buckets;
@@ -1,7 +1,9 @@
 use std::fmt;
 use std::from_str::FromStr;
-use std::cmp;
 use std::option::{Option, Some, None};
+use std::result::{Result, Ok, Err};
+use std::slice;
+use std::str;
 
 
 /// All known metric types that we can send or receive.
@@ -9,8 +11,11 @@ use std::option::{Option, Some, None};
 pub enum MetricKind {
     Counter(f64), // sample rate
     Gauge,
-    Timer,
-    Histogram
+    GaugeDelta, // relative adjustment of an existing gauge
+    Timer(f64), // sample rate
+    Histogram(f64), // sample rate
+    Distribution(f64), // sample rate
+    Set
 }
 
 
@@ -18,9 +23,48 @@ impl fmt::Show for MetricKind {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match *self {
             Gauge      => write!(f.buf, "Gauge"),
-            Timer      => write!(f.buf, "Timer"),
-            Histogram  => write!(f.buf, "Histogram"),
-            Counter(s) => write!(f.buf, "Counter(s={})", s)
+            GaugeDelta => write!(f.buf, "GaugeDelta"),
+            Set        => write!(f.buf, "Set"),
+            Counter(s)   => write!(f.buf, "Counter(s={})", s),
+            Timer(s)     => write!(f.buf, "Timer(s={})", s),
+            Histogram(s) => write!(f.buf, "Histogram(s={})", s),
+            Distribution(s) => write!(f.buf, "Distribution(s={})", s)
+        }
+    }
+}
+
+
+impl MetricKind {
+    /// The rate at which this kind of metric was sampled. Kinds that can't
+    /// be sampled always have a rate of 1.
+    pub fn sample_rate(&self) -> f64 {
+        match *self {
+            Counter(s) | Timer(s) | Histogram(s) | Distribution(s) => s,
+            _ => 1.0
+        }
+    }
+
+    /// The type section of the wire format for this kind.
+    pub fn type_str(&self) -> &'static str {
+        match *self {
+            Counter(_) => "c",
+            Timer(_) => "ms",
+            Histogram(_) => "h",
+            Distribution(_) => "d",
+            Gauge | GaugeDelta => "g",
+            Set => "s"
+        }
+    }
+
+    /// The same kind sampled at `sample_rate` instead. Kinds that can't be
+    /// sampled are returned as they are.
+    pub fn with_sample_rate(&self, sample_rate: f64) -> MetricKind {
+        match *self {
+            Counter(_) => Counter(sample_rate),
+            Timer(_) => Timer(sample_rate),
+            Histogram(_) => Histogram(sample_rate),
+            Distribution(_) => Distribution(sample_rate),
+            kind => kind
         }
     }
 }
@@ -31,66 +75,482 @@ impl fmt::Show for MetricKind {
 pub struct Metric {
     pub kind: MetricKind,
     pub name: ~str,
-    pub value: f64
+    pub value: f64,
+    /// The member added to a set, exactly as it was sent. Sets have no
+    /// numeric value.
+    pub member: Option<~str>,
+    /// DogStatsD style `key:value` tags, in the order they were sent. Bare
+    /// tags have an empty value.
+    pub tags: ~[(~str, ~str)],
+    /// Unix timestamp supplied by the client, if any.
+    pub timestamp: Option<i64>
 }
 
 
 impl fmt::Show for Metric {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f.buf, "{}({}) => {}", self.name, self.kind, self.value)
+        match self.member {
+            Some(ref member) => try!(write!(f.buf, "{}({}) => {}", self.name, self.kind, *member)),
+            None => try!(write!(f.buf, "{}({}) => {}", self.name, self.kind, self.value))
+        }
+
+        for &(ref key, ref value) in self.tags.iter() {
+            try!(write!(f.buf, " {}={}", *key, *value));
+        }
+
+        for ts in self.timestamp.iter() {
+            try!(write!(f.buf, " @ {}", *ts));
+        }
+
+        Ok(())
+    }
+}
+
+
+/// Identifies a single series, on the server or in a client side
+/// `Aggregator`: a metric name along with its tags.
+///
+/// Tags are kept sorted so that the same tag set sent in a different order
+/// ends up in the same series.
+#[deriving(Eq, TotalEq, Hash, Clone)]
+pub struct Key {
+    pub name: ~str,
+    pub tags: ~[(~str, ~str)]
+}
+
+
+impl Key {
+    pub fn new(name: ~str, tags: ~[(~str, ~str)]) -> Key {
+        let mut tags = tags;
+        tags.sort();
+
+        Key { name: name, tags: tags }
+    }
+}
+
+
+/// Reasons a line sent by a client can fail to parse.
+#[deriving(Eq, TotalEq, Hash, Clone)]
+pub enum ParseError {
+    InvalidUtf8,
+    MissingName,
+    MissingValue,
+    BadValue,
+    MissingType,
+    UnknownType,
+    BadSampleRate,
+    UnsampledType, // sample rate given for a gauge or set
+    BadTags,
+    BadTimestamp,
+    UnknownSection,
+    BadEvent,
+    BadServiceCheck
+}
+
+
+impl fmt::Show for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let desc = match *self {
+            InvalidUtf8    => "invalid utf-8",
+            MissingName    => "missing name",
+            MissingValue   => "missing value",
+            BadValue       => "bad value",
+            MissingType    => "missing type",
+            UnknownType    => "unknown type",
+            BadSampleRate  => "bad sample rate",
+            UnsampledType  => "sample rate on unsampled type",
+            BadTags        => "bad tags",
+            BadTimestamp   => "bad timestamp",
+            UnknownSection => "unknown section",
+            BadEvent       => "bad event",
+            BadServiceCheck => "bad service check"
+        };
+
+        write!(f.buf, "{}", desc)
+    }
+}
+
+
+/// Replace each of the `reserved` characters in `s` with `_`.
+fn sanitize(s: &str, reserved: &[char]) -> ~str {
+    s.chars().map(|c| if reserved.contains(&c) { '_' } else { c }).collect()
+}
+
+
+/// The prefix prepended to a client's keys: `prefix` and a `.`, or nothing
+/// at all for an empty `prefix`.
+pub fn key_prefix(prefix: &str) -> ~str {
+    if prefix.is_empty() { ~"" } else { format!("{}.", prefix) }
+}
+
+
+/// Split a single `key:value` tag. A bare `key` is a tag with an empty
+/// value, but a key followed by `:` needs a value.
+fn split_tag<'a>(tag: &'a str) -> Option<(&'a str, &'a str)> {
+    match tag.find(':') {
+        Some(pos) if pos != 0 && pos != tag.len() - 1 => {
+            Some((tag.slice_to(pos), tag.slice_from(pos + 1)))
+        },
+        None if !tag.is_empty() => Some((tag, "")),
+        _ => None
+    }
+}
+
+
+/// Parse the comma separated `key:value` pairs and bare `key`s of a tag
+/// section.
+pub fn parse_tags<'a>(section: &'a str) -> Result<~[(&'a str, &'a str)], ParseError> {
+    let mut tags = ~[];
+
+    for tag in section.split(',') {
+        match split_tag(tag) {
+            Some(pair) => tags.push(pair),
+            None => return Err(BadTags)
+        }
+    }
+
+    Ok(tags)
+}
+
+
+/// The tags of a `MetricRef`, borrowed from wherever the metric came from
+/// without being copied.
+pub enum Tags<'a> {
+    NoTags,
+    /// The tag section of a parsed line, which is split up as it is
+    /// iterated over. Only sections that have been checked by the parser
+    /// are kept like this.
+    TagSection(&'a str),
+    /// Tags that are already split up, such as those of a `Metric`.
+    TagPairs(&'a [(~str, ~str)])
+}
+
+
+impl<'a> Tags<'a> {
+    pub fn iter(&self) -> TagIter<'a> {
+        match *self {
+            NoTags => EmptyIter,
+            TagSection(section) => SectionIter(section.split(',')),
+            TagPairs(pairs) => PairIter(pairs.iter())
+        }
+    }
+
+    pub fn len(&self) -> uint {
+        self.iter().fold(0, |len, _| len + 1)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.iter().next().is_none()
+    }
+}
+
+
+impl<'a> Eq for Tags<'a> {
+    fn eq(&self, other: &Tags<'a>) -> bool {
+        let mut ours = self.iter();
+        let mut theirs = other.iter();
+
+        loop {
+            match (ours.next(), theirs.next()) {
+                (None, None) => return true,
+                (Some(a), Some(b)) if a == b => {},
+                _ => return false
+            }
+        }
     }
 }
 
-impl FromStr for Metric {
 
+/// Iterates over the `(key, value)` pairs of `Tags`.
+pub enum TagIter<'a> {
+    EmptyIter,
+    SectionIter(str::CharSplits<'a, char>),
+    PairIter(slice::Items<'a, (~str, ~str)>)
+}
+
+
+impl<'a> Iterator<(&'a str, &'a str)> for TagIter<'a> {
+    fn next(&mut self) -> Option<(&'a str, &'a str)> {
+        match *self {
+            EmptyIter => None,
+            // The parser has already made sure that every tag splits.
+            SectionIter(ref mut tags) => tags.next().and_then(|tag| split_tag(tag)),
+            PairIter(ref mut pairs) => {
+                pairs.next().map(|&(ref key, ref value)| (key.as_slice(), value.as_slice()))
+            }
+        }
+    }
+}
+
+
+impl Metric {
+    /// Create an untagged metric. Sets are created with `new_set` instead.
+    pub fn new(kind: MetricKind, name: &str, value: f64) -> Metric {
+        Metric {
+            kind: kind,
+            name: name.to_owned(),
+            value: value,
+            member: None,
+            tags: ~[],
+            timestamp: None
+        }
+    }
+
+    /// Create an untagged metric adding `member` to the set `name`.
+    pub fn new_set(name: &str, member: &str) -> Metric {
+        Metric { member: Some(member.to_owned()), ..Metric::new(Set, name, 0.0) }
+    }
+
+    /// Encode the metric in the statsd wire format understood by
+    /// `Metric::parse`.
+    ///
+    /// The protocol can't express an absolute gauge with a negative value,
+    /// since a sign means a relative update. Such gauges are encoded as two
+    /// lines, first resetting the gauge to zero and then subtracting from it.
+    ///
+    /// Characters that would split up the line differently, such as a `:` in
+    /// the name or a `,` in a tag, are replaced with `_`.
+    pub fn encode(&self) -> ~str {
+        let name = self.encode_name();
+        let type_str = self.kind.type_str();
+        let suffix = self.encode_suffix();
+
+        match self.kind {
+            GaugeDelta if !self.value.is_negative() =>
+                format!("{name}:+{value}|g{suffix}",
+                        name=name, value=self.value, suffix=suffix),
+            Gauge if self.value.is_negative() =>
+                format!("{name}:0|g{suffix}\n{name}:{value}|g{suffix}",
+                        name=name, value=self.value, suffix=suffix),
+            Set => {
+                let member = self.member.as_ref()
+                    .map_or(~"", |member| sanitize(member.as_slice(), ['|', '\n']));
+                format!("{name}:{member}|s{suffix}",
+                        name=name, member=member, suffix=suffix)
+            },
+            _ =>
+                format!("{name}:{value}|{kind}{suffix}",
+                        name=name, value=self.value, kind=type_str, suffix=suffix)
+        }
+    }
+
+    /// Encode the name, which ends at the first `:`.
+    pub fn encode_name(&self) -> ~str {
+        sanitize(self.name.as_slice(), [':', '|', '\n'])
+    }
+
+    /// Encode the sections that follow the type: the sample rate (if it
+    /// isn't 1), the tags and the timestamp.
+    pub fn encode_suffix(&self) -> ~str {
+        let mut suffix = ~"";
+
+        let sample_rate = self.kind.sample_rate();
+        if sample_rate != 1.0 {
+            suffix.push_str(format!("|@{}", sample_rate));
+        }
+
+        if !self.tags.is_empty() {
+            // A tag's key ends at its first `:`, but its value may hold more.
+            // Tags without a value are sent bare.
+            let tags: ~[~str] = self.tags.iter()
+                .map(|&(ref key, ref value)| {
+                    let key = sanitize(key.as_slice(), [':', ',', '|', '\n']);
+
+                    if value.is_empty() {
+                        key
+                    } else {
+                        format!("{}:{}", key, sanitize(value.as_slice(), [',', '|', '\n']))
+                    }
+                })
+                .collect();
+
+            suffix.push_str("|#");
+            suffix.push_str(tags.connect(","));
+        }
+
+        for ts in self.timestamp.iter() {
+            suffix.push_str(format!("|T{}", *ts));
+        }
+
+        suffix
+    }
+
+    /// Parse a single line sent by a client. See `MetricRef::parse` for the
+    /// accepted formats.
+    pub fn parse(line: &str) -> Result<Metric, ParseError> {
+        MetricRef::parse(line).map(|metric| metric.to_metric())
+    }
+
+    /// Parse a single line straight out of a received packet.
+    pub fn parse_bytes(buf: &[u8]) -> Result<Metric, ParseError> {
+        MetricRef::parse_bytes(buf).map(|metric| metric.to_metric())
+    }
+
+    /// Borrow the metric's name and tags as a `MetricRef`.
+    pub fn as_ref<'a>(&'a self) -> MetricRef<'a> {
+        MetricRef {
+            kind: self.kind,
+            name: self.name.as_slice(),
+            value: self.value,
+            member: self.member.as_ref().map(|member| member.as_slice()),
+            tags: TagPairs(self.tags.as_slice()),
+            timestamp: self.timestamp
+        }
+    }
+}
+
+
+/// A metric parsed in place, borrowing its name, tags and set member from the
+/// line it was read from.
+///
+/// This is what the server parses incoming packets into, so that nothing
+/// needs to be allocated for metrics whose series already exist.
+#[deriving(Eq)]
+pub struct MetricRef<'a> {
+    pub kind: MetricKind,
+    pub name: &'a str,
+    pub value: f64,
+    pub member: Option<&'a str>,
+    pub tags: Tags<'a>,
+    pub timestamp: Option<i64>
+}
+
+
+impl<'a> MetricRef<'a> {
+    /// Parse a single line sent by a client.
+    ///
     /// Valid message formats are:
     ///
     /// - `<str:metric_name>:<f64:value>|<str:type>`
-    /// - `<str:metric_name>:<f64:value>|c|@<f64:sample_rate>`
-    fn from_str(line: &str) -> Option<Metric> {
-        // Pointer to position in line
-        let mut idx = 0u;
-
-        let name = match line.find(':') {
+    /// - `<str:metric_name>:<f64:value>|<c|ms|h|d>|@<f64:sample_rate>`
+    ///
+    /// Either format may be followed by a `|#<str:key>:<str:value>,...`
+    /// section of tags, where a tag may also be a bare `<str:key>`, and a
+    /// `|T<i64:unix_timestamp>` section. Gauge values prefixed with `+` or `-` are treated as
+    /// relative adjustments rather than absolute values.
+    pub fn parse(line: &'a str) -> Result<MetricRef<'a>, ParseError> {
+        let (name, rest) = match line.find(':') {
             // We don't want to allow blank key names.
-            Some(pos) if pos != 0 => {
-                idx += pos + 1;
-                line.slice_to(pos).to_owned()
-            },
+            Some(0) => return Err(MissingName),
+            Some(pos) => (line.slice_to(pos), line.slice_from(pos + 1)),
+            None => return Err(MissingValue)
+        };
+
+        let mut sections = rest.split('|');
 
-            _ => return None
+        let raw_value = match sections.next() {
+            Some("") | None => return Err(MissingValue),
+            Some(v) => v
         };
 
-        // Try to parse `<f64>|`, return None if no match is found.
-        let value_opt = line.slice_from(idx).find('|').and_then(|loc| {
-            FromStr::from_str(line.slice(idx, idx + loc)).map(|val| {
-                idx += loc + 1;
-                val
-            })
-        });
-
-        let value = match value_opt {
-            Some(v) => v,
-            None => return None
+        let signed = raw_value.starts_with("+") || raw_value.starts_with("-");
+        let unsigned = if raw_value.starts_with("+") {
+            raw_value.slice_from(1)
+        } else {
+            raw_value
         };
 
-        let end_idx = cmp::min(idx + 3, line.len());
-
-        let kind = match line.slice(idx, end_idx) {
-            "c" => Counter(1.0),
-            "ms" => Timer,
-            "h" => Histogram,
-            "g" => Gauge,
-            // Sampled counter
-            "c|@" => match FromStr::from_str(line.slice_from(end_idx)) {
-                Some(sample) => Counter(sample),
-                None => return None
-            },
+        let type_section = sections.next();
+
+        // Set members are kept as they were sent, so that IDs that aren't
+        // numbers, or are too large to be told apart as floats, still count
+        // as distinct.
+        let (value, member) = if type_section == Some("s") {
+            (0.0, Some(raw_value))
+        } else {
+            match FromStr::from_str(unsigned) {
+                Some(v) => (v, None),
+                None => return Err(BadValue)
+            }
+        };
 
-            // Unknown type
-            _ => return None
+        let mut kind = match type_section {
+            Some("c") => Counter(1.0),
+            Some("ms") => Timer(1.0),
+            Some("h") => Histogram(1.0),
+            Some("d") => Distribution(1.0),
+            Some("g") if signed => GaugeDelta,
+            Some("g") => Gauge,
+            Some("s") => Set,
+            Some("") | None => return Err(MissingType),
+            Some(_) => return Err(UnknownType)
         };
 
-        Some(Metric { kind: kind, name: name, value: value })
+        let mut tags = NoTags;
+        let mut timestamp = None;
+
+        for section in sections {
+            if section.starts_with("@") {
+                let sample = match FromStr::from_str(section.slice_from(1)) {
+                    Some(sample) if sample > 0.0 => sample,
+                    _ => return Err(BadSampleRate)
+                };
+
+                // Gauges and sets can't be sampled.
+                kind = match kind {
+                    Counter(_) => Counter(sample),
+                    Timer(_) => Timer(sample),
+                    Histogram(_) => Histogram(sample),
+                    Distribution(_) => Distribution(sample),
+                    _ => return Err(UnsampledType)
+                };
+            } else if section.starts_with("#") {
+                let section = section.slice_from(1);
+
+                // Only check the tags for now. They're split up again
+                // when they're used, which saves collecting them here.
+                if !section.split(',').all(|tag| split_tag(tag).is_some()) {
+                    return Err(BadTags);
+                }
+
+                tags = TagSection(section);
+            } else if section.starts_with("T") {
+                timestamp = match FromStr::from_str(section.slice_from(1)) {
+                    Some(ts) => Some(ts),
+                    None => return Err(BadTimestamp)
+                };
+            } else {
+                return Err(UnknownSection);
+            }
+        }
+
+        Ok(MetricRef {
+            kind: kind,
+            name: name,
+            value: value,
+            member: member,
+            tags: tags,
+            timestamp: timestamp
+        })
+    }
+
+    /// Parse a single line straight out of a received packet.
+    pub fn parse_bytes(buf: &'a [u8]) -> Result<MetricRef<'a>, ParseError> {
+        match str::from_utf8(buf) {
+            Some(line) => MetricRef::parse(line),
+            None => Err(InvalidUtf8)
+        }
+    }
+
+    /// Copy the borrowed name and tags into an owned `Metric`.
+    pub fn to_metric(&self) -> Metric {
+        Metric {
+            kind: self.kind,
+            name: self.name.to_owned(),
+            value: self.value,
+            member: self.member.map(|member| member.to_owned()),
+            tags: self.tags.iter()
+                .map(|(key, value)| (key.to_owned(), value.to_owned()))
+                .collect(),
+            timestamp: self.timestamp
+        }
+    }
+}
+
+
+impl FromStr for Metric {
+    /// See `MetricRef::parse` for the accepted formats.
+    fn from_str(line: &str) -> Option<Metric> {
+        Metric::parse(line).ok()
     }
 }
@@ -0,0 +1,60 @@
+//! Deciding which samples of a sampled metric are sent.
+
+use rand::{Rng, SeedableRng, XorShiftRng, task_rng};
+
+
+/// Decides whether a sample taken at some rate should be sent.
+pub trait Sampler {
+    /// Return true to send a sample of a metric sampled at `sample_rate`.
+    fn sample(&mut self, sample_rate: f64) -> bool;
+}
+
+
+/// Sends each sample with a probability of its sample rate. This is what a
+/// `Client` uses unless told otherwise.
+pub struct RandomSampler {
+    rng: XorShiftRng
+}
+
+
+impl RandomSampler {
+    /// Create a sampler seeded from the task's random number generator.
+    pub fn new() -> RandomSampler {
+        RandomSampler { rng: task_rng().gen() }
+    }
+
+    /// Create a sampler that always makes the same decisions for the same
+    /// `seed`, which must not be all zeroes.
+    pub fn new_seeded(seed: [u32, ..4]) -> RandomSampler {
+        RandomSampler { rng: SeedableRng::from_seed(seed) }
+    }
+}
+
+
+impl Sampler for RandomSampler {
+    fn sample(&mut self, sample_rate: f64) -> bool {
+        sample_rate >= 1.0 || self.rng.gen::<f64>() < sample_rate
+    }
+}
+
+
+/// Sends every sample, whatever its rate.
+pub struct AlwaysSample;
+
+
+impl Sampler for AlwaysSample {
+    fn sample(&mut self, _sample_rate: f64) -> bool {
+        true
+    }
+}
+
+
+/// Never sends a sampled metric. Metrics with a rate of 1 are still sent.
+pub struct NeverSample;
+
+
+impl Sampler for NeverSample {
+    fn sample(&mut self, sample_rate: f64) -> bool {
+        sample_rate >= 1.0
+    }
+}
@@ -1,3 +1,4 @@
+use event;
 use server::buckets;
 
 
@@ -9,4 +10,11 @@ pub trait Backend {
     /// Called on server `flush` events, which occur on a timer (every 10
     /// seconds by default).
     fn flush_buckets(&mut self, &buckets::Buckets) -> ();
+
+    /// Handle the events and service checks received since the last flush.
+    ///
+    /// Called right after `flush_buckets`. Backends that have no use for
+    /// events can rely on the default, which ignores them.
+    fn flush_events(&mut self, _events: &[event::Event],
+                    _checks: &[event::ServiceCheck]) -> () {}
 }
\ No newline at end of file
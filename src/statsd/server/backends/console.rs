@@ -1,6 +1,8 @@
 //! Print out a YAML representation of the server's buckets to the attached
 //! terminal on every flush event.
 
+use event::{Event, ServiceCheck};
+use metric::Key;
 use server::backend::Backend;
 use server::buckets::Buckets;
 
@@ -26,28 +28,48 @@ impl Console {
         }
     }
 
-    fn fmt_line<T: fmt::Show>(&mut self, key: &str, value: T) {
-        println!("    {}: {}", key, value)
+    fn fmt_line<T: fmt::Show>(&mut self, key: &Key, value: T) {
+        if key.tags.is_empty() {
+            println!("    {}: {}", key.name, value)
+        } else {
+            println!("    {}:", key.name);
+            print_tags(key);
+            println!("      value: {}", value);
+        }
+    }
+}
+
+
+/// Tagged series are printed as a nested map of the tags.
+fn print_tags(key: &Key) {
+    if key.tags.is_empty() {
+        return;
+    }
+
+    println!("      tags:");
+    for &(ref tag, ref value) in key.tags.iter() {
+        println!("        {}: {}", *tag, *value);
     }
 }
 
 
 /// Code common to both Histograms and Timers (because they're the same)
-fn print_stats(hist: &HashMap<~str, ~[f64]>) {
+fn print_stats(hist: &HashMap<Key, ~[f64]>, counts: &HashMap<Key, f64>) {
     for (key, values) in hist.iter() {
         let samples: &[f64] = *values;
+        let count = counts.find(key).map_or(samples.len() as f64, |c| *c);
 
-        println!("    {key}:
-      min: {min}
+        println!("    {}:", key.name);
+        print_tags(key);
+        println!("      min: {min}
       max: {max}
       count: {count}
       mean: {mean}
       stddev: {std}
       upper_95: {max_threshold}",
-                 key=*key,
                  min=samples.min(),
                  max=samples.max(),
-                 count=samples.len(),
+                 count=count,
                  mean=samples.mean(),
                  std=samples.std_dev(),
                  max_threshold=samples.percentile(95.0));
@@ -61,18 +83,54 @@ impl Backend for Console {
 
         println!("  counters:");
         for (key, value) in buckets.counters.iter() {
-            self.fmt_line(*key, *value);
+            self.fmt_line(key, *value);
         }
 
         println!("  gauges:");
         for (key, value) in buckets.gauges.iter() {
-            self.fmt_line(*key, *value);
+            self.fmt_line(key, *value);
         }
 
         println!("  timers:");
-        print_stats(&buckets.timers);
+        print_stats(&buckets.timers, &buckets.timer_counts);
 
         println!("  histograms:");
-        print_stats(&buckets.histograms);
+        print_stats(&buckets.histograms, &buckets.histogram_counts);
+
+        println!("  sets:");
+        for (key, values) in buckets.sets.iter() {
+            self.fmt_line(key, values.len());
+        }
+
+        println!("  timestamped:");
+        for sample in buckets.timestamped.iter() {
+            println!("    - {}", *sample);
+        }
+    }
+
+    fn flush_events(&mut self, events: &[Event], checks: &[ServiceCheck]) -> () {
+        println!("  events:");
+        for event in events.iter() {
+            println!("    - title: {}", event.title);
+            println!("      text: {}", event.text.escape_default());
+
+            for alert_type in event.alert_type.iter() {
+                println!("      alert_type: {}", *alert_type);
+            }
+
+            for hostname in event.hostname.iter() {
+                println!("      hostname: {}", *hostname);
+            }
+        }
+
+        println!("  service_checks:");
+        for check in checks.iter() {
+            println!("    - name: {}", check.name);
+            println!("      status: {}", check.status);
+
+            for message in check.message.iter() {
+                println!("      message: {}", *message);
+            }
+        }
     }
-}
\ No newline at end of file
+}
@@ -1,5 +1,7 @@
 //! Export data to a specified graphite instance over TCP.
 
+use metric;
+use metric::Key;
 use server::backend::Backend;
 use server::buckets::Buckets;
 
@@ -43,14 +45,71 @@ impl Graphite {
         }
     }
 
-    fn fmt_line<T: fmt::Show>(&mut self, key: &str, value: T, time: i64) -> ~str {
+    fn fmt_line<T: fmt::Show>(&self, key: &str, value: T, time: i64) -> ~str {
         format!("{}{} {} {}\n", self.prefix, key, value, time)
     }
+
+    /// Format samples sent with their own timestamp as individual points,
+    /// using `now` for any sample without one.
+    ///
+    /// Graphite only keeps the last value written for a path and timestamp,
+    /// so counters sharing both are summed into a single point. Only counters
+    /// are scaled by their sample rate.
+    pub fn fmt_timestamped(&self, samples: &[metric::Metric], now: i64) -> ~str {
+        let mut str_buf = ~"";
+        let mut counters: HashMap<(~str, i64), f64> = HashMap::new();
+
+        for sample in samples.iter() {
+            let key = Key::new(sample.name.clone(), sample.tags.clone());
+            let ts = sample.timestamp.unwrap_or(now);
+
+            let kind = match sample.kind {
+                metric::Counter(sample_rate) => {
+                    let value = sample.value / sample_rate;
+                    counters.insert_or_update_with((fmt_key("counters", &key, ""), ts),
+                                                   value, |_, v| *v += value);
+                    continue;
+                },
+                metric::Gauge => "gauges",
+                metric::Timer(_) => "timers",
+                metric::Histogram(_) | metric::Distribution(_) => "histograms",
+                // These are aggregated by `Buckets` rather than kept as points.
+                metric::GaugeDelta | metric::Set => continue
+            };
+
+            str_buf.push_str(self.fmt_line(fmt_key(kind, &key, ""), sample.value, ts));
+        }
+
+        for (&(ref path, ts), value) in counters.iter() {
+            str_buf.push_str(self.fmt_line(path.as_slice(), *value, ts));
+        }
+
+        str_buf
+    }
+}
+
+
+/// Build the Graphite path for `key`, with tags appended in Graphite's
+/// `path;tag=value` format. Graphite needs a value for every tag, so bare
+/// tags are given `true`.
+fn fmt_key(kind: &str, key: &Key, stat: &str) -> ~str {
+    let mut path = format!("{}.{}{}", kind, key.name, stat);
+
+    for &(ref tag, ref value) in key.tags.iter() {
+        let value = if value.is_empty() { "true" } else { value.as_slice() };
+        path.push_str(format!(";{}={}", *tag, value));
+    }
+
+    path
 }
 
 
 /// Abstract out formatting code for both histograms and timers.
-fn fmt_stats(start: i64, hist_kind: &str, hist: &HashMap<~str, ~[f64]>) -> ~str {
+///
+/// `counts` holds the sample-rate adjusted number of samples for each key,
+/// which is also reported per second of the `interval`.
+fn fmt_stats(start: i64, hist_kind: &str, hist: &HashMap<Key, ~[f64]>,
+             counts: &HashMap<Key, f64>, interval: f64) -> ~str {
     let mut str_buf = ~"";
 
     for (key, values) in hist.iter() {
@@ -58,23 +117,20 @@ fn fmt_stats(start: i64, hist_kind: &str, hist: &HashMap<~str, ~[f64]>) -> ~str
         //      statistical value.
 
         let samples: &[f64] = *values;
-        let key = format!("{}.{}", hist_kind, *key);
-
-        let line = format!("{key}.min {min} {ts}
-{key}.max {max} {ts}
-{key}.count {count} {ts}
-{key}.mean {mean} {ts}
-{key}.stddev {std} {ts}
-{key}.upper_95 {max_threshold} {ts}\n",
-                           key=key, ts=start,
-                           min=samples.min(),
-                           max=samples.max(),
-                           count=samples.len(),
-                           mean=samples.mean(),
-                           std=samples.std_dev(),
-                           max_threshold=samples.percentile(95.0));
-
-        str_buf.push_str(line);
+        let count = counts.find(key).map_or(samples.len() as f64, |c| *c);
+
+        let stats = [(".min", samples.min()),
+                     (".max", samples.max()),
+                     (".count", count),
+                     (".count_ps", count / interval),
+                     (".mean", samples.mean()),
+                     (".stddev", samples.std_dev()),
+                     (".upper_95", samples.percentile(95.0))];
+
+        for &(stat, value) in stats.iter() {
+            let line = format!("{} {} {}\n", fmt_key(hist_kind, key, stat), value, start);
+            str_buf.push_str(line);
+        }
     }
 
     str_buf
@@ -87,17 +143,28 @@ impl Backend for Graphite {
         let mut str_buf = ~"";
 
         for (key, value) in buckets.counters.iter() {
-            let key = format!("counters.{}", *key);
+            let key = fmt_key("counters", key, "");
             str_buf.push_str(self.fmt_line(key, *value, start));
         }
 
         for (key, value) in buckets.gauges.iter() {
-            let key = format!("gauges.{}", *key);
+            let key = fmt_key("gauges", key, "");
             str_buf.push_str(self.fmt_line(key, *value, start));
         }
 
-        str_buf.push_str(fmt_stats(start, "timers", &buckets.timers));
-        str_buf.push_str(fmt_stats(start, "histograms", &buckets.histograms));
+        let interval = buckets.flush_interval();
+
+        str_buf.push_str(fmt_stats(start, "timers", &buckets.timers,
+                                   &buckets.timer_counts, interval));
+        str_buf.push_str(fmt_stats(start, "histograms", &buckets.histograms,
+                                   &buckets.histogram_counts, interval));
+
+        for (key, values) in buckets.sets.iter() {
+            let key = fmt_key("sets", key, ".count");
+            str_buf.push_str(self.fmt_line(key, values.len(), start));
+        }
+
+        str_buf.push_str(self.fmt_timestamped(buckets.timestamped, start));
 
         str_buf.push_str(self.fmt_line(
             "graphiteStats.last_flush", self.last_flush_time, start));
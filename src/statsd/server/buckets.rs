@@ -1,25 +1,136 @@
 use metric;
+use metric::Key;
+use event;
 
-use collections::hashmap::HashMap;
+use std::cmp::Equiv;
+use std::str;
+
+use collections::hashmap::{HashMap, HashSet};
 
 use time;
 
 
+/// Number of tags a series can have for it to be looked up without
+/// allocating.
+static MAX_STACK_TAGS: uint = 16;
+
+
+/// Borrowed counterpart of `Key`, used to look up series without allocating.
+///
+/// The fields mirror `Key` so that both derive the same hash.
+#[deriving(Hash)]
+pub struct KeyRef<'a> {
+    pub name: &'a str,
+    pub tags: &'a [(&'a str, &'a str)]
+}
+
+
+impl<'a> KeyRef<'a> {
+    pub fn to_key(&self) -> Key {
+        Key {
+            name: self.name.to_owned(),
+            tags: self.tags.iter()
+                .map(|&(key, value)| (key.to_owned(), value.to_owned()))
+                .collect()
+        }
+    }
+}
+
+
+/// Call `f` with the key of the series `name` and `tags`. The tags are sorted
+/// on the stack, unless there are too many of them.
+fn with_key_ref<T>(name: &str, tags: &metric::Tags, f: |&KeyRef| -> T) -> T {
+    let len = tags.len();
+
+    if len > MAX_STACK_TAGS {
+        let mut sorted: ~[(&str, &str)] = tags.iter().collect();
+        sorted.sort();
+
+        return f(&KeyRef { name: name, tags: sorted.as_slice() });
+    }
+
+    let mut buf = [("", ""), ..MAX_STACK_TAGS];
+    for (slot, tag) in buf.mut_iter().zip(tags.iter()) {
+        *slot = tag;
+    }
+
+    buf.mut_slice_to(len).sort();
+
+    f(&KeyRef { name: name, tags: buf.slice_to(len) })
+}
+
+
+impl<'a> Equiv<Key> for KeyRef<'a> {
+    fn equiv(&self, other: &Key) -> bool {
+        self.name == other.name.as_slice() &&
+            self.tags.len() == other.tags.len() &&
+            self.tags.iter().zip(other.tags.iter()).all(|(&(k1, v1), &(ref k2, ref v2))| {
+                k1 == k2.as_slice() && v1 == v2.as_slice()
+            })
+    }
+}
+
+
+/// Update the value stored for `key`, only allocating an owned key when the
+/// series doesn't exist yet.
+fn upsert<V>(map: &mut HashMap<Key, V>, key: &KeyRef, init: || -> V, update: |&mut V|) {
+    match map.find_mut_equiv(key) {
+        Some(v) => {
+            update(v);
+            return;
+        },
+        None => {}
+    }
+
+    map.insert(key.to_key(), init());
+}
+
+
 /// Buckets are the main storage of the statsd server. Each bucket is a simple
 /// hashmap representing the key: value pairs that the statsd clients send to this
-/// server.
+/// server. Each unique name and tag set combination is stored separately.
 ///
-/// The buckets are cleared out on every flush event.
+/// The buckets are cleared out on every flush event, except for gauges, which
+/// keep their last value until they are explicitly cleared.
 pub struct Buckets {
-    pub counters:   HashMap<~str, f64>,
-    pub gauges:     HashMap<~str, f64>,
-    pub histograms: HashMap<~str, ~[f64]>,
-    pub timers:     HashMap<~str, ~[f64]>,
+    pub counters:   HashMap<Key, f64>,
+    pub gauges:     HashMap<Key, f64>,
+    pub histograms: HashMap<Key, ~[f64]>,
+    pub timers:     HashMap<Key, ~[f64]>,
+    pub sets:       HashMap<Key, HashSet<~str>>,
+
+    /// Number of samples received for each timer and histogram, scaled by
+    /// the sample rate of each sample.
+    pub timer_counts:     HashMap<Key, f64>,
+    pub histogram_counts: HashMap<Key, f64>,
+
+    /// Events and service checks received during the current interval.
+    pub events:         ~[event::Event],
+    pub service_checks: ~[event::ServiceCheck],
+
+    /// Most recent service check received for each check name, kept across
+    /// flushes for the `health` management command.
+    pub latest_checks: HashMap<~str, event::ServiceCheck>,
+
+    /// Samples carrying their own timestamp, when `keep_timestamps` is set.
+    /// These are passed on to the backends as individual points instead of
+    /// being aggregated. Gauge deltas and sets are always aggregated.
+    pub timestamped: ~[metric::Metric],
+    pub keep_timestamps: bool,
+
+    /// Samples with a timestamp more than this many seconds in the past are
+    /// dropped.
+    pub max_sample_age: Option<i64>,
 
     pub server_start_time: time::Timespec,
     pub last_message: time::Timespec,
+    pub last_flush: time::Timespec,
     pub bad_messages: uint,
-    pub total_messages: uint
+    pub total_messages: uint,
+    pub stale_messages: uint,
+
+    /// Number of bad messages received for each kind of parse error.
+    pub parse_errors: HashMap<metric::ParseError, uint>
 }
 
 
@@ -30,20 +141,54 @@ impl Buckets {
             gauges: HashMap::new(),
             histograms: HashMap::new(),
             timers: HashMap::new(),
+            sets: HashMap::new(),
+
+            timer_counts: HashMap::new(),
+            histogram_counts: HashMap::new(),
+
+            events: ~[],
+            service_checks: ~[],
+            latest_checks: HashMap::new(),
+
+            timestamped: ~[],
+            keep_timestamps: false,
+            max_sample_age: None,
 
             server_start_time: time::get_time(),
             last_message: time::get_time(),
+            last_flush: time::get_time(),
             bad_messages: 0,
-            total_messages: 0
+            total_messages: 0,
+            stale_messages: 0,
+
+            parse_errors: HashMap::new()
         }
     }
 
-    /// Clear out current buckets
+    /// Clear out current buckets. Gauges are kept so that relative updates
+    /// in the next interval apply to their last known value.
     pub fn flush(&mut self) {
         self.counters.clear();
-        self.gauges.clear();
         self.histograms.clear();
         self.timers.clear();
+        self.sets.clear();
+        self.timer_counts.clear();
+        self.histogram_counts.clear();
+        self.events.clear();
+        self.service_checks.clear();
+        self.timestamped.clear();
+
+        self.last_flush = time::get_time();
+    }
+
+    /// Number of seconds covered by the current flush interval.
+    pub fn flush_interval(&self) -> f64 {
+        let now = time::get_time();
+        let secs = (now.sec - self.last_flush.sec) as f64 +
+            (now.nsec - self.last_flush.nsec) as f64 / 1e9;
+
+        // Avoid dividing by zero when computing rates.
+        if secs > 0.0 { secs } else { 1.0 }
     }
 
     /// Act on a line of text sent to the management server.
@@ -58,10 +203,32 @@ impl Buckets {
             "stats" => {
                 let uptime = time::get_time().sec - self.server_start_time.sec;
 
-                format!("uptime: {up} s\nbad messages: {bad}total messages: {total}",
-                        up=uptime,
-                        bad=self.bad_messages,
-                        total=self.total_messages)
+                let mut stats = format!("uptime: {up} s\nbad messages: {bad}",
+                                        up=uptime,
+                                        bad=self.bad_messages);
+
+                for (err, count) in self.parse_errors.iter() {
+                    stats.push_str(format!("\n  {}: {}", *err, *count));
+                }
+
+                stats.push_str(format!("\nstale messages: {}", self.stale_messages));
+                stats.push_str(format!("\ntotal messages: {}", self.total_messages));
+                stats
+            },
+            "health" => {
+                let mut health = format!("events: {events}\nservice checks: {checks}",
+                                         events=self.events.len(),
+                                         checks=self.service_checks.len());
+
+                for (name, check) in self.latest_checks.iter() {
+                    health.push_str(format!("\n  {}: {}", *name, check.status));
+
+                    for message in check.message.iter() {
+                        health.push_str(format!(" - {}", *message));
+                    }
+                }
+
+                health
             },
             "clear" => {
                 match words.next().unwrap_or("") {
@@ -75,12 +242,18 @@ impl Buckets {
                     },
                     "histograms" => {
                         self.histograms.clear();
+                        self.histogram_counts.clear();
                         ~"Histograms cleared."
                     },
                     "timers" => {
                         self.timers.clear();
+                        self.timer_counts.clear();
                         ~"Timers cleared."
                     },
+                    "sets" => {
+                        self.sets.clear();
+                        ~"Sets cleared."
+                    },
                     "" => ~"ERROR: need something to clear!",
                     x => format!("ERROR: Nothing named '{}' to clear.", x)
                 }
@@ -95,33 +268,141 @@ impl Buckets {
         (resp, false)
     }
 
+    /// Split a packet received from a client into newline separated lines,
+    /// adding each valid metric, event or service check to the proper bucket
+    /// and counting the rest as bad messages.
+    pub fn add_packet(&mut self, buf: &[u8]) {
+        for line in buf.split(|b| *b == '\n' as u8) {
+            // A trailing newline leaves an empty line behind, which isn't an
+            // error.
+            if line.is_empty() {
+                continue;
+            }
+
+            let result = match str::from_utf8(line) {
+                None => Err(metric::InvalidUtf8),
+                Some(line) if line.starts_with("_e{") => {
+                    event::Event::parse(line).map(|event| self.add_event(event))
+                },
+                Some(line) if line.starts_with("_sc|") => {
+                    event::ServiceCheck::parse(line).map(|check| self.add_service_check(check))
+                },
+                Some(line) => {
+                    metric::MetricRef::parse(line).map(|metric| self.add_metric_ref(&metric))
+                }
+            };
+
+            match result {
+                Ok(()) => {},
+                Err(err) => self.add_bad_message(err)
+            }
+        }
+    }
+
+    /// Store an event until the next flush.
+    pub fn add_event(&mut self, event: event::Event) {
+        self.events.push(event);
+
+        self.last_message = time::get_time();
+        self.total_messages += 1;
+    }
+
+    /// Store a service check until the next flush, and remember it as the
+    /// latest status of the check.
+    pub fn add_service_check(&mut self, check: event::ServiceCheck) {
+        self.latest_checks.insert(check.name.clone(), check.clone());
+        self.service_checks.push(check);
+
+        self.last_message = time::get_time();
+        self.total_messages += 1;
+    }
+
+    /// Record a line that couldn't be parsed.
+    pub fn add_bad_message(&mut self, err: metric::ParseError) {
+        self.parse_errors.insert_or_update_with(err, 1, |_, count| *count += 1);
+        self.bad_messages += 1;
+    }
+
     /// Add `metric` to the proper bucket.
     pub fn add_metric(&mut self, metric: metric::Metric) {
-        let key = metric.name.clone();
+        self.add_metric_ref(&metric.as_ref());
+    }
+
+    /// Add a borrowed `metric` to the proper bucket. Its name and tags are
+    /// only copied when a new series is created.
+    pub fn add_metric_ref(&mut self, metric: &metric::MetricRef) {
+        match metric.timestamp {
+            Some(ts) => {
+                let too_old = self.max_sample_age.map_or(false, |age| {
+                    time::get_time().sec - ts > age
+                });
+
+                if too_old {
+                    self.stale_messages += 1;
+                    return;
+                }
+
+                // Gauge deltas and set members only mean something relative
+                // to the current interval, so they are aggregated as usual.
+                let is_point = match metric.kind {
+                    metric::GaugeDelta | metric::Set => false,
+                    _ => true
+                };
+
+                if self.keep_timestamps && is_point {
+                    self.timestamped.push(metric.to_metric());
+
+                    self.last_message = time::get_time();
+                    self.total_messages += 1;
+                    return;
+                }
+            },
+            None => {}
+        }
+
+        with_key_ref(metric.name, &metric.tags, |key| self.add_to_series(metric, key));
+
+        self.last_message = time::get_time();
+        self.total_messages += 1;
+    }
+
+    /// Add the value of `metric` to the series stored under `key`.
+    fn add_to_series(&mut self, metric: &metric::MetricRef, key: &KeyRef) {
         let val = metric.value;
 
         match metric.kind {
             metric::Counter(sample_rate) => {
-                self.counters
-                    .insert_or_update_with(key, 0.0, |_, v| {
-                        *v += val * (1.0 / sample_rate)
-                    }
-                );
+                let val = val * (1.0 / sample_rate);
+                upsert(&mut self.counters, key, || val, |v| *v += val);
             },
             metric::Gauge => {
-                self.gauges.insert(key, val);
+                upsert(&mut self.gauges, key, || val, |v| *v = val);
+            },
+            // A gauge that hasn't been set yet starts out at zero.
+            metric::GaugeDelta => {
+                upsert(&mut self.gauges, key, || val, |v| *v += val);
             },
-            metric::Timer => {
-                self.timers.insert_or_update_with(key, ~[], |_, v| v.push(val));
+            metric::Timer(sample_rate) => {
+                let count = 1.0 / sample_rate;
+                upsert(&mut self.timer_counts, key, || count, |v| *v += count);
+                upsert(&mut self.timers, key, || ~[val], |v| v.push(val));
             },
             // Histograms are functionally equivalent to Timers with a
-            // different name.
-            metric::Histogram => {
-                self.histograms.insert_or_update_with(key, ~[], |_, v| v.push(val));
+            // different name. Distributions are aggregated like histograms.
+            metric::Histogram(sample_rate) | metric::Distribution(sample_rate) => {
+                let count = 1.0 / sample_rate;
+                upsert(&mut self.histogram_counts, key, || count, |v| *v += count);
+                upsert(&mut self.histograms, key, || ~[val], |v| v.push(val));
+            },
+            // Sets only track the distinct members seen for each key during
+            // the current flush interval. Members are only copied when they
+            // haven't been seen yet.
+            metric::Set => {
+                let member = metric.member.unwrap_or("");
+                upsert(&mut self.sets, key,
+                       || { let mut set = HashSet::new(); set.insert(member.to_owned()); set },
+                       |set| if !set.contains_equiv(&member) { set.insert(member.to_owned()); });
             }
         }
-
-        self.last_message = time::get_time();
-        self.total_messages += 1;
     }
 }
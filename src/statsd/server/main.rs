@@ -11,6 +11,7 @@ use statsd::server::backend::Backend;
 use statsd::server::backends::console::Console;
 use statsd::server::backends::graphite::Graphite;
 use statsd::server::buckets::Buckets;
+use statsd::unix::UnixDatagram;
 
 use std::from_str::FromStr;
 use std::io;
@@ -22,23 +23,23 @@ use std::option::{Some, None};
 use std::result::{Ok, Err};
 use std::os;
 use std::comm;
-use std::str;
+use std::num::from_str_radix;
 
 use sync::{Mutex, Arc};
 use getopts::{optopt, optflag, getopts};
 
 
 static FLUSH_INTERVAL_MS: u64 = 10000;
-static MAX_PACKET_SIZE: uint = 256;
+static MAX_PACKET_SIZE: uint = 8932;
 
 static DEFAULT_UDP_PORT: u16 = 8125;
 static DEFAULT_TCP_PORT: u16 = 8126;
+static DEFAULT_SOCKET_MODE: u32 = 0o660;
 
 
 /// Different kinds of events we accept in the main event loop.
 enum Event {
     FlushTimer,
-    UdpMessage(~[u8]),
     TcpMessage(~tcp::TcpStream)
 }
 
@@ -93,7 +94,11 @@ fn management_server_loop(chan: comm::Sender<~Event>, port: u16) {
 
 
 /// Accept incoming UDP data from statsd clients.
-fn udp_server_loop(chan: comm::Sender<~Event>, port: u16) {
+///
+/// Packets are parsed straight out of the receive buffer, which only
+/// allocates to create new series and set members or to grow the samples kept
+/// for timers and histograms.
+fn udp_server_loop(buckets_arc: Arc<Mutex<Buckets>>, port: u16) {
     let addr = SocketAddr { ip: Ipv4Addr(0, 0, 0, 0), port: port };
     let mut socket = UdpSocket::bind(addr).unwrap();
     let mut buf = [0u8, ..MAX_PACKET_SIZE];
@@ -106,9 +111,28 @@ fn udp_server_loop(chan: comm::Sender<~Event>, port: u16) {
                 println!("Max packet size exceeded.");
             }
 
-            // Use the slice to strip out trailing \0 characters
-            let msg = buf.slice_to(nread).to_owned();
-            chan.send(~UdpMessage(msg));
+            // Use the slice to strip out trailing \0 characters.
+            let mut buckets = buckets_arc.lock();
+            buckets.add_packet(buf.slice_to(nread));
+        });
+    }
+}
+
+/// Accept incoming data from statsd clients on a Unix datagram socket, feeding
+/// it into the same buckets as UDP packets.
+fn unix_server_loop(buckets_arc: Arc<Mutex<Buckets>>, socket: UnixDatagram) {
+    let mut socket = socket;
+    let mut buf = [0u8, ..MAX_PACKET_SIZE];
+
+    loop {
+        // TODO: Should handle errors here
+        let _ = socket.recv(buf).map(|nread| {
+            if nread == MAX_PACKET_SIZE {
+                println!("Max packet size exceeded.");
+            }
+
+            let mut buckets = buckets_arc.lock();
+            buckets.add_packet(buf.slice_to(nread));
         });
     }
 }
@@ -123,8 +147,16 @@ Port will default to 2003 if not specified.");
 UDP port. Defaults to {}.", DEFAULT_UDP_PORT);
     println!("  --admin-port port       Have the admin server listen on this \
 TCP port. Defaults to {}.", DEFAULT_TCP_PORT);
+    println!("  --socket path           Also listen on a Unix datagram socket at \
+this path.");
+    println!("  --socket-mode mode      Permissions of the Unix socket, in \
+octal. Defaults to {:o}.", DEFAULT_SOCKET_MODE);
     println!("  --flush                 Flush interval, in seconds. Defaults \
 to {}.", FLUSH_INTERVAL_MS / 1000);
+    println!("  --keep-timestamps       Pass samples sent with a timestamp on \
+to the backends as individual points.");
+    println!("  --max-age seconds       Drop samples with a timestamp older \
+than this.");
 }
 
 
@@ -137,7 +169,11 @@ fn main() {
         optflag("", "console", "Enable Console output"),
         optopt("", "port", "UDP port for statsd to server listen on", "PORT"),
         optopt("", "admin-port", "TCP port to have admin server listen on", "PORT"),
-        optopt("", "flush", "Flush interval, in seconds.", "SECONDS")
+        optopt("", "socket", "Unix datagram socket to listen on", "PATH"),
+        optopt("", "socket-mode", "Permissions of the Unix socket, in octal", "MODE"),
+        optopt("", "flush", "Flush interval, in seconds.", "SECONDS"),
+        optflag("", "keep-timestamps", "Keep timestamped samples as individual points"),
+        optopt("", "max-age", "Drop timestamped samples older than this, in seconds.", "SECONDS")
     ];
 
     let matches = match getopts(args.tail(), opts) {
@@ -223,18 +259,69 @@ fn main() {
         None => FLUSH_INTERVAL_MS
     };
 
+    let socket_mode = match matches.opt_str("socket-mode") {
+        Some(mode_str) => match from_str_radix::<u32>(mode_str, 8) {
+            Some(mode) => mode,
+            None => {
+                println!("Invalid mode: {}", mode_str);
+                return print_usage();
+            }
+        },
+        None => DEFAULT_SOCKET_MODE
+    };
+
+    let max_sample_age = match matches.opt_str("max-age") {
+        Some(str_secs) => match from_str::<i64>(str_secs) {
+            Some(secs) => Some(secs),
+            None => {
+                println!("Invalid integer: {}", str_secs);
+                return print_usage();
+            }
+        },
+        None => None
+    };
+
+    // Bind before spawning anything, since the server can't exit while other
+    // tasks are running.
+    let unix_socket = match matches.opt_str("socket") {
+        Some(path) => {
+            let path = Path::new(path);
+
+            match UnixDatagram::bind(&path, socket_mode as io::FilePermission) {
+                Ok(socket) => {
+                    println!("Listening on unix socket {}.", path.display());
+                    Some(socket)
+                },
+                Err(err) => {
+                    println!("Can't listen on unix socket {}: {}", path.display(), err);
+                    os::set_exit_status(1);
+                    return;
+                }
+            }
+        },
+        None => None
+    };
+
+    let mut buckets = Buckets::new();
+    buckets.keep_timestamps = matches.opt_present("keep-timestamps");
+    buckets.max_sample_age = max_sample_age;
+
+    let buckets_arc = Arc::new(Mutex::new(buckets));
+
     let (event_send, event_recv) = comm::channel::<~Event>();
 
     let flush_send = event_send.clone();
     let mgmt_send = event_send.clone();
-    let udp_send = event_send.clone();
+    let udp_buckets = buckets_arc.clone();
 
     spawn(proc() { flush_timer_loop(flush_send, flush_interval) });
     spawn(proc() { management_server_loop(mgmt_send, tcp_port) });
-    spawn(proc() { udp_server_loop(udp_send, udp_port) });
+    spawn(proc() { udp_server_loop(udp_buckets, udp_port) });
 
-    let buckets = Buckets::new();
-    let buckets_arc = Arc::new(Mutex::new(buckets));
+    for socket in unix_socket.move_iter() {
+        let unix_buckets = buckets_arc.clone();
+        spawn(proc() { unix_server_loop(unix_buckets, socket) });
+    }
 
     // Main event loop.
     loop {
@@ -245,6 +332,8 @@ fn main() {
 
                 for ref mut backend in backends.mut_iter() {
                     backend.flush_buckets(&*buckets);
+                    backend.flush_events(buckets.events.as_slice(),
+                                         buckets.service_checks.as_slice());
                 }
 
                 buckets.flush();
@@ -257,15 +346,6 @@ fn main() {
 
                 // Spin up a new thread to handle the TCP stream.
                 spawn(proc() { management_connection_loop(s, buckets_arc) });
-            },
-
-            // UDP message received
-            UdpMessage(buf) => {
-                let mut buckets = buckets_arc.lock();
-                str::from_utf8(buf)
-                    .and_then(|string| FromStr::from_str(string))
-                    .map(|metric| buckets.add_metric(metric))
-                    .or_else(|| { buckets.bad_messages += 1; None });
             }
         }
     }
@@ -0,0 +1,263 @@
+//! A client that can be shared between tasks.
+
+use std::io::IoResult;
+use std::io::net::ip::SocketAddr;
+use std::local_data;
+use std::sync::atomics::{AtomicUint, SeqCst};
+
+use metric;
+use metric::Metric;
+use sampler::{Sampler, RandomSampler};
+use sink::MetricSink;
+use udp::UdpSender;
+
+
+/** A statsd client whose methods only need a shared reference.
+
+Unlike `Client`, it can be put in an `Arc` (or any other shared location) and
+used from every task at once, without a lock. Each metric is sent as its own
+UDP datagram through a socket shared by all tasks, and sampling decisions are
+made by a `Sampler` kept in task-local storage, so each task has its own.
+
+A task sending a lot of metrics can skip the task-local lookup by sending
+through a `SharedSink` of its own, which keeps its sampler with it.
+
+The prefix, default tags and samplers are set up front, before the client is
+shared. Failed sends are counted, but the errors themselves aren't kept.
+*/
+pub struct SharedClient {
+    sock: UdpSender,
+    prefix: ~str,
+    tags: ~[(~str, ~str)],
+    new_sampler: fn() -> ~Sampler,
+
+    send_errors: AtomicUint
+}
+
+
+/// Create the sampler a `SharedClient` uses unless told otherwise.
+fn random_sampler() -> ~Sampler {
+    box RandomSampler::new() as ~Sampler
+}
+
+
+/// The current task's sampler, along with the address of the function that
+/// made it.
+local_data_key!(task_sampler: (uint, ~Sampler))
+
+
+/// Decide whether to send a sample taken at `sample_rate` with the current
+/// task's sampler, which is made by `new_sampler` the first time it's needed.
+fn task_sample(new_sampler: fn() -> ~Sampler, sample_rate: f64) -> bool {
+    let id = new_sampler as uint;
+
+    let sampled = local_data::get_mut(task_sampler, |slot| {
+        match slot {
+            Some(slot) => {
+                let (slot_id, ref mut sampler) = *slot;
+
+                if slot_id == id { Some(sampler.sample(sample_rate)) } else { None }
+            },
+            None => None
+        }
+    });
+
+    match sampled {
+        Some(sampled) => sampled,
+        None => {
+            // The first sample of this task, or the client was given a
+            // different sampler function.
+            let mut sampler = new_sampler();
+            let sampled = sampler.sample(sample_rate);
+            local_data::set(task_sampler, (id, sampler));
+
+            sampled
+        }
+    }
+}
+
+
+impl SharedClient {
+    /// Construct a client sending to `dest`. Fails if a local socket can't
+    /// be created.
+    pub fn new(dest: SocketAddr) -> IoResult<SharedClient> {
+        let sock = try!(UdpSender::new(dest));
+
+        Ok(SharedClient {
+            sock: sock,
+            prefix: ~"",
+            tags: ~[],
+            new_sampler: random_sampler,
+
+            send_errors: AtomicUint::new(0)
+        })
+    }
+
+    /// Create with a prefix that will be automatically prepended to all keys.
+    /// An empty prefix is ignored.
+    pub fn new_with_prefix(prefix: &str, dest: SocketAddr) -> IoResult<SharedClient> {
+        let mut client = try!(SharedClient::new(dest));
+        client.prefix = metric::key_prefix(prefix);
+
+        Ok(client)
+    }
+
+    /// Add a tag that will be sent along with every metric.
+    pub fn add_default_tag(&mut self, key: &str, value: &str) {
+        self.tags.push((key.to_owned(), value.to_owned()));
+    }
+
+    /// Give every task, sink and handle a sampler made by `new_sampler`,
+    /// instead of a `RandomSampler`.
+    pub fn set_sampler_fn(&mut self, new_sampler: fn() -> ~Sampler) {
+        self.new_sampler = new_sampler;
+    }
+
+    /// Make a sampler for a new sink or handle.
+    pub fn new_sampler(&self) -> ~Sampler {
+        (self.new_sampler)()
+    }
+
+    /// A sink sending through this client, with a sampler of its own instead
+    /// of the task's. Each task should use its own.
+    pub fn sink<'a>(&'a self) -> SharedSink<'a> {
+        SharedSink { client: self, sampler: self.new_sampler() }
+    }
+
+    /// The prefix prepended to every key, including its trailing `.`.
+    pub fn prefix<'a>(&'a self) -> &'a str {
+        self.prefix.as_slice()
+    }
+
+    /// The tags sent along with every metric.
+    pub fn default_tags<'a>(&'a self) -> &'a [(~str, ~str)] {
+        self.tags.as_slice()
+    }
+
+    /// Number of packets that failed to send, from any task.
+    pub fn send_errors(&self) -> uint {
+        self.send_errors.load(SeqCst)
+    }
+
+    /// Increment the given `name` by one with a probability of `sample_rate`.
+    pub fn incr(&self, name: &str, sample_rate: f64) {
+        self.count_sampled(name, 1.0, sample_rate);
+    }
+
+    /// Decrement the given `name` by one with a probability of `sample_rate`.
+    pub fn decr(&self, name: &str, sample_rate: f64) {
+        self.count_sampled(name, -1.0, sample_rate);
+    }
+
+    /// Add `value` to the given `name`.
+    pub fn count(&self, name: &str, value: f64) {
+        self.count_sampled(name, value, 1.0);
+    }
+
+    /// Add `value` to the given `name` with a probability of `sample_rate`.
+    pub fn count_sampled(&self, name: &str, value: f64, sample_rate: f64) {
+        self.send_metric(Metric::new(metric::Counter(sample_rate), name, value));
+    }
+
+    /// Simply set the given `name` to `value`.
+    pub fn gauge(&self, name: &str, value: f64) {
+        self.send_metric(Metric::new(metric::Gauge, name, value));
+    }
+
+    /// Add `value` to the current value of the gauge `name`.
+    pub fn gauge_incr(&self, name: &str, value: f64) {
+        self.send_metric(Metric::new(metric::GaugeDelta, name, value));
+    }
+
+    /// Subtract `value` from the current value of the gauge `name`.
+    pub fn gauge_decr(&self, name: &str, value: f64) {
+        self.send_metric(Metric::new(metric::GaugeDelta, name, -value));
+    }
+
+    /// Specify that this instance of `name` took `ms` milliseconds.
+    pub fn time(&self, name: &str, ms: uint) {
+        self.send_metric(Metric::new(metric::Timer(1.0), name, ms as f64));
+    }
+
+    /// Append `val` to the vector `name`.
+    pub fn hist(&self, name: &str, val: f64) {
+        self.send_metric(Metric::new(metric::Histogram(1.0), name, val));
+    }
+
+    /// Add `value` to the distribution `name` with a probability of
+    /// `sample_rate`.
+    pub fn distribution(&self, name: &str, value: f64, sample_rate: f64) {
+        self.send_metric(Metric::new(metric::Distribution(sample_rate), name, value));
+    }
+
+    /// Add `member` to the set `name`.
+    pub fn set(&self, name: &str, member: &str) {
+        self.send_metric(Metric::new_set(name, member));
+    }
+
+    /// Send `metric` with a probability of its sample rate.
+    pub fn send_metric(&self, metric: Metric) {
+        self.send_sampled(metric);
+    }
+
+    /// Data goes in, data comes out. With a probability decided by the
+    /// current task's sampler.
+    fn send_sampled(&self, metric: Metric) {
+        if task_sample(self.new_sampler, metric.kind.sample_rate()) {
+            self.write_metric(metric);
+        }
+    }
+
+    /// Apply the prefix and default tags to `metric`, then send it.
+    fn write_metric(&self, metric: Metric) {
+        let mut metric = metric;
+
+        if !self.prefix.is_empty() {
+            metric.name = format!("{}{}", self.prefix, metric.name);
+        }
+
+        metric.tags.push_all(self.tags.as_slice());
+
+        self.send_packet(metric.encode().as_bytes());
+    }
+
+    /// Send `data`, holding one or more encoded metrics separated by
+    /// newlines, as a single datagram. Failures are counted.
+    pub fn send_packet(&self, data: &[u8]) {
+        if self.sock.send(data).is_err() {
+            self.send_errors.fetch_add(1, SeqCst);
+        }
+    }
+}
+
+
+impl MetricSink for SharedClient {
+    fn send_metric(&mut self, metric: Metric) {
+        self.send_sampled(metric);
+    }
+}
+
+
+/// Sends metrics through a `SharedClient`, deciding which samples are sent
+/// with a sampler of its own.
+pub struct SharedSink<'a> {
+    client: &'a SharedClient,
+    sampler: ~Sampler
+}
+
+
+impl<'a> SharedSink<'a> {
+    /// Decide which samples are sent with `sampler` instead.
+    pub fn set_sampler(&mut self, sampler: ~Sampler) {
+        self.sampler = sampler;
+    }
+}
+
+
+impl<'a> MetricSink for SharedSink<'a> {
+    fn send_metric(&mut self, metric: Metric) {
+        if self.sampler.sample(metric.kind.sample_rate()) {
+            self.client.write_metric(metric);
+        }
+    }
+}
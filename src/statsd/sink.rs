@@ -0,0 +1,187 @@
+//! Anything metrics can be sent to.
+
+use std::mem;
+
+use metric;
+use metric::Metric;
+
+use time;
+
+
+/** Somewhere to send metrics.
+
+Only `send_metric` has to be implemented; the rest of the API is built on top
+of it. Code that emits metrics can be written against this trait, so that tests
+can swap the real `Client` for a `CaptureSink` and check what was sent.
+*/
+pub trait MetricSink {
+    /// Send `metric`, with a probability of its sample rate.
+    fn send_metric(&mut self, metric: Metric);
+
+    /// Send anything held back by the sink right away.
+    fn flush(&mut self) {}
+
+    /// Sample rate of the metrics sent without one of their own, through
+    /// `count`, `time`, `time_block` and `hist`.
+    fn default_sample_rate(&self) -> f64 {
+        1.0
+    }
+
+    /// Increment the given `name` by one with a probability of `sample_rate`.
+    fn incr(&mut self, name: &str, sample_rate: f64) {
+        self.count_sampled(name, 1.0, sample_rate);
+    }
+
+    /// Decrement the given `name` by one with a probability of `sample_rate`.
+    fn decr(&mut self, name: &str, sample_rate: f64) {
+        self.count_sampled(name, -1.0, sample_rate);
+    }
+
+    /// Add `value` to the given `name`, at the default sample rate.
+    fn count(&mut self, name: &str, value: f64) {
+        let sample_rate = self.default_sample_rate();
+        self.count_sampled(name, value, sample_rate);
+    }
+
+    /// Add `value` to the given `name` with a probability of `sample_rate`.
+    fn count_sampled(&mut self, name: &str, value: f64, sample_rate: f64) {
+        self.send_metric(Metric::new(metric::Counter(sample_rate), name, value));
+    }
+
+    /// Simply set the given `name` to `value`.
+    fn gauge(&mut self, name: &str, value: f64) {
+        self.send_metric(Metric::new(metric::Gauge, name, value));
+    }
+
+    /** Specify that this instance of `name` took `ms` milliseconds.
+
+    It doesn't matter what you use here. Statsd bizarrely treats timed values
+    specially. A better name for this kind of value would be `histogram`,
+    because that's what's really being calculated from the server side. Some
+    server implementations (such as the one included here) support histogram
+    keys.
+
+    Sent at the default sample rate.
+    */
+    fn time(&mut self, name: &str, ms: uint) {
+        let sample_rate = self.default_sample_rate();
+        self.send_metric(Metric::new(metric::Timer(sample_rate), name, ms as f64));
+    }
+
+    /// Similar to `time`, but the `ms` value sent is the amount of time taken
+    /// to execute the proc.
+    fn time_block(&mut self, name: &str, block: proc()) {
+        let timer = self.start_timer(name);
+        block();
+        timer.stop(self);
+    }
+
+    /// Like `time_block`, but returns whatever `block` returns.
+    fn time_fn<T>(&mut self, name: &str, block: || -> T) -> T {
+        let timer = self.start_timer(name);
+        let result = block();
+        timer.stop(self);
+
+        result
+    }
+
+    /// Start timing `name`. The elapsed time is sent once the returned timer
+    /// is stopped. The timer doesn't borrow the sink, which stays free to
+    /// send other metrics in the meantime.
+    fn start_timer(&self, name: &str) -> RunningTimer {
+        RunningTimer {
+            name: name.to_owned(),
+            start_ns: time::precise_time_ns()
+        }
+    }
+
+    /// Append `val` to the vector `name`. Server will generate summary
+    /// statistics for the vector on each flush. Sent at the default sample
+    /// rate.
+    fn hist(&mut self, name: &str, val: f64) {
+        let sample_rate = self.default_sample_rate();
+        self.send_metric(Metric::new(metric::Histogram(sample_rate), name, val));
+    }
+
+    /// Add `value` to the distribution `name` with a probability of
+    /// `sample_rate`. Servers without distributions treat these as
+    /// histograms.
+    fn distribution(&mut self, name: &str, value: f64, sample_rate: f64) {
+        self.send_metric(Metric::new(metric::Distribution(sample_rate), name, value));
+    }
+
+    /// Add `member` to the set `name`. The server counts the distinct
+    /// members seen in each flush interval.
+    fn set(&mut self, name: &str, member: &str) {
+        self.send_metric(Metric::new_set(name, member));
+    }
+
+    /// Add `value` to the current value of the gauge `name`.
+    fn gauge_incr(&mut self, name: &str, value: f64) {
+        self.send_metric(Metric::new(metric::GaugeDelta, name, value));
+    }
+
+    /// Subtract `value` from the current value of the gauge `name`.
+    fn gauge_decr(&mut self, name: &str, value: f64) {
+        self.send_metric(Metric::new(metric::GaugeDelta, name, -value));
+    }
+}
+
+
+/// A timer started by `MetricSink::start_timer`. Nothing is sent until it is
+/// stopped, through whichever sink is passed to `stop`.
+pub struct RunningTimer {
+    name: ~str,
+    start_ns: u64
+}
+
+
+impl RunningTimer {
+    /// Milliseconds elapsed since the timer was started.
+    pub fn elapsed_ms(&self) -> uint {
+        ((time::precise_time_ns() - self.start_ns) / 1000000) as uint
+    }
+
+    /// Send the time elapsed so far to `sink`, returning it in milliseconds.
+    pub fn stop<S: MetricSink>(self, sink: &mut S) -> uint {
+        let elapsed_ms = self.elapsed_ms();
+        sink.time(self.name.as_slice(), elapsed_ms);
+
+        elapsed_ms
+    }
+}
+
+
+/// Throws every metric away.
+pub struct NoopSink;
+
+
+impl MetricSink for NoopSink {
+    fn send_metric(&mut self, _metric: Metric) {}
+}
+
+
+/// Keeps every metric sent to it, for tests to inspect. Sampled metrics are
+/// always kept, along with their sample rate.
+pub struct CaptureSink {
+    pub metrics: ~[Metric]
+}
+
+
+impl CaptureSink {
+    pub fn new() -> CaptureSink {
+        CaptureSink { metrics: ~[] }
+    }
+
+    /// Return the metrics captured so far, starting over with none.
+    pub fn take(&mut self) -> ~[Metric] {
+        mem::replace(&mut self.metrics, ~[])
+    }
+}
+
+
+impl MetricSink for CaptureSink {
+    fn send_metric(&mut self, metric: Metric) {
+        self.metrics.push(metric);
+    }
+}
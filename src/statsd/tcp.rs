@@ -0,0 +1,139 @@
+//! TCP connections that give up on connecting and writing after a timeout,
+//! which the standard library doesn't provide.
+
+use libc::{c_int, c_short, c_ulong, c_void, size_t, socklen_t};
+use libc::{sockaddr, sockaddr_storage, AF_INET, AF_INET6, SOCK_STREAM};
+use libc::{SOL_SOCKET, SO_ERROR, F_GETFL, F_SETFL, O_NONBLOCK, EINPROGRESS, EAGAIN, EINTR};
+use libc::{socket, connect, send, fcntl, getsockopt, close};
+
+use std::io;
+use std::io::{IoError, IoResult};
+use std::io::net::ip::{Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::mem;
+use std::os;
+
+use udp;
+
+
+struct pollfd {
+    fd: c_int,
+    events: c_short,
+    revents: c_short
+}
+
+static POLLOUT: c_short = 0x4;
+
+extern {
+    fn poll(fds: *mut pollfd, nfds: c_ulong, timeout: c_int) -> c_int;
+}
+
+
+/// A non-blocking TCP connection that waits at most `timeout_ms` for the
+/// server whenever it can't make progress.
+pub struct TcpSender {
+    fd: c_int,
+    timeout_ms: u64
+}
+
+
+impl TcpSender {
+    /// Connect to `dest`, failing if that takes longer than `timeout_ms`.
+    /// Writes wait for the server for at most as long.
+    pub fn connect(dest: SocketAddr, timeout_ms: u64) -> IoResult<TcpSender> {
+        let domain = match dest.ip {
+            Ipv4Addr(..) => AF_INET,
+            Ipv6Addr(..) => AF_INET6
+        };
+        let (addr, len) = udp::sockaddr(dest);
+
+        let fd = unsafe { socket(domain, SOCK_STREAM, 0) };
+        if fd < 0 {
+            return Err(IoError::last_error());
+        }
+
+        // Closes the socket if anything below fails.
+        let sock = TcpSender { fd: fd, timeout_ms: timeout_ms };
+
+        let flags = unsafe { fcntl(fd, F_GETFL, 0) };
+        if flags < 0 || unsafe { fcntl(fd, F_SETFL, flags | O_NONBLOCK) } < 0 {
+            return Err(IoError::last_error());
+        }
+
+        let ret = unsafe {
+            connect(fd, &addr as *sockaddr_storage as *sockaddr, len)
+        };
+
+        if ret < 0 {
+            if os::errno() as c_int != EINPROGRESS {
+                return Err(IoError::last_error());
+            }
+
+            try!(sock.wait_writable("connecting timed out"));
+
+            let mut err: c_int = 0;
+            let mut err_len = mem::size_of::<c_int>() as socklen_t;
+            let ret = unsafe {
+                getsockopt(fd, SOL_SOCKET, SO_ERROR, &mut err as *mut c_int as *mut c_void,
+                           &mut err_len)
+            };
+
+            if ret < 0 {
+                return Err(IoError::last_error());
+            } else if err != 0 {
+                return Err(IoError::from_errno(err as uint, false));
+            }
+        }
+
+        Ok(sock)
+    }
+
+    /// Write all of `buf`, failing if the server stops taking data for longer
+    /// than the timeout. A failed write may have written part of `buf`.
+    pub fn write(&mut self, buf: &[u8]) -> IoResult<()> {
+        let mut buf = buf;
+
+        while !buf.is_empty() {
+            let ret = unsafe {
+                send(self.fd, buf.as_ptr() as *c_void, buf.len() as size_t, 0)
+            };
+
+            if ret >= 0 {
+                buf = buf.slice_from(ret as uint);
+                continue;
+            }
+
+            match os::errno() as c_int {
+                EAGAIN => try!(self.wait_writable("writing timed out")),
+                EINTR => {},
+                _ => return Err(IoError::last_error())
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Wait until the socket can be written to, for at most the timeout.
+    fn wait_writable(&self, desc: &'static str) -> IoResult<()> {
+        let mut fds = pollfd { fd: self.fd, events: POLLOUT, revents: 0 };
+
+        loop {
+            match unsafe { poll(&mut fds, 1, self.timeout_ms as c_int) } {
+                0 => return Err(IoError {
+                    kind: io::ResourceUnavailable,
+                    desc: desc,
+                    detail: None
+                }),
+                ret if ret < 0 && os::errno() as c_int == EINTR => {},
+                ret if ret < 0 => return Err(IoError::last_error()),
+                _ => return Ok(())
+            }
+        }
+    }
+}
+
+
+impl Drop for TcpSender {
+    fn drop(&mut self) {
+        unsafe { close(self.fd); }
+    }
+}
@@ -1,26 +1,51 @@
 extern crate statsd;
+extern crate rand;
+extern crate test;
+extern crate time;
+extern crate sync;
 
 
 #[cfg(test)]
 mod metric {
     use statsd::metric;
-    use statsd::metric::Metric;
+    use statsd::metric::{Metric, MetricRef};
 
     use std::from_str::FromStr;
+    use rand::{task_rng, Rng};
 
     #[test]
     fn test_from_str_valid_input() {
         let in_out_map = ~[
-            ("f.o.o:1|c",      Metric {kind: metric::Counter(1.0), name: ~"f.o.o", value: 1.0}),
-            ("foo:9.1|c|@0.5", Metric {kind: metric::Counter(0.5), name: ~"foo", value: 9.1}),
-            ("foo:2|c|@1",     Metric {kind: metric::Counter(1.0), name: ~"foo", value: 2.0}),
-            ("foo:2|c|@123",   Metric {kind: metric::Counter(123.0), name: ~"foo", value: 2.0}),
-            ("foo:12.3|ms",    Metric {kind: metric::Timer, name: ~"foo", value: 12.3}),
-            ("foo:1|ms",       Metric {kind: metric::Timer, name: ~"foo", value: 1.0}),
-            ("foo:1|h",        Metric {kind: metric::Histogram, name: ~"foo", value: 1.0}),
-            ("foo:1.23|h",     Metric {kind: metric::Histogram, name: ~"foo", value: 1.23}),
-            ("foo:1|g",        Metric {kind: metric::Gauge, name: ~"foo", value: 1.0}),
-            ("foo:1.23|g",     Metric {kind: metric::Gauge, name: ~"foo", value: 1.23})
+            ("f.o.o:1|c",      Metric {kind: metric::Counter(1.0), name: ~"f.o.o", value: 1.0, member: None, tags: ~[], timestamp: None}),
+            ("foo:9.1|c|@0.5", Metric {kind: metric::Counter(0.5), name: ~"foo", value: 9.1, member: None, tags: ~[], timestamp: None}),
+            ("foo:2|c|@1",     Metric {kind: metric::Counter(1.0), name: ~"foo", value: 2.0, member: None, tags: ~[], timestamp: None}),
+            ("foo:2|c|@123",   Metric {kind: metric::Counter(123.0), name: ~"foo", value: 2.0, member: None, tags: ~[], timestamp: None}),
+            ("foo:12.3|ms",    Metric {kind: metric::Timer(1.0), name: ~"foo", value: 12.3, member: None, tags: ~[], timestamp: None}),
+            ("foo:1|ms",       Metric {kind: metric::Timer(1.0), name: ~"foo", value: 1.0, member: None, tags: ~[], timestamp: None}),
+            ("foo:12|ms|@0.1", Metric {kind: metric::Timer(0.1), name: ~"foo", value: 12.0, member: None, tags: ~[], timestamp: None}),
+            ("foo:3|h|@0.25",  Metric {kind: metric::Histogram(0.25), name: ~"foo", value: 3.0, member: None, tags: ~[], timestamp: None}),
+            ("foo:1|h",        Metric {kind: metric::Histogram(1.0), name: ~"foo", value: 1.0, member: None, tags: ~[], timestamp: None}),
+            ("foo:1.23|h",     Metric {kind: metric::Histogram(1.0), name: ~"foo", value: 1.23, member: None, tags: ~[], timestamp: None}),
+            ("foo:4|d",        Metric {kind: metric::Distribution(1.0), name: ~"foo", value: 4.0, member: None, tags: ~[], timestamp: None}),
+            ("foo:4|d|@0.5",   Metric {kind: metric::Distribution(0.5), name: ~"foo", value: 4.0, member: None, tags: ~[], timestamp: None}),
+            ("foo:1|g",        Metric {kind: metric::Gauge, name: ~"foo", value: 1.0, member: None, tags: ~[], timestamp: None}),
+            ("foo:1.23|g",     Metric {kind: metric::Gauge, name: ~"foo", value: 1.23, member: None, tags: ~[], timestamp: None}),
+            ("foo:+5|g",       Metric {kind: metric::GaugeDelta, name: ~"foo", value: 5.0, member: None, tags: ~[], timestamp: None}),
+            ("foo:-3.5|g",     Metric {kind: metric::GaugeDelta, name: ~"foo", value: -3.5, member: None, tags: ~[], timestamp: None}),
+            ("foo:42|s",       Metric::new_set("foo", "42")),
+            ("foo:+ab12|s",    Metric::new_set("foo", "+ab12")),
+            ("foo:1|c|#a:b",   Metric {kind: metric::Counter(1.0), name: ~"foo", value: 1.0, member: None,
+                                       tags: ~[(~"a", ~"b")], timestamp: None}),
+            ("foo:1|c|@0.5|#b:c,a:d", Metric {kind: metric::Counter(0.5), name: ~"foo", value: 1.0, member: None,
+                                              tags: ~[(~"b", ~"c"), (~"a", ~"d")], timestamp: None}),
+            ("foo:1|ms|#a:b:c", Metric {kind: metric::Timer(1.0), name: ~"foo", value: 1.0, member: None,
+                                        tags: ~[(~"a", ~"b:c")], timestamp: None}),
+            ("foo:1|c|#canary", Metric {kind: metric::Counter(1.0), name: ~"foo", value: 1.0, member: None,
+                                        tags: ~[(~"canary", ~"")], timestamp: None}),
+            ("foo:1|c|#env:prod,canary", Metric {kind: metric::Counter(1.0), name: ~"foo", value: 1.0, member: None,
+                                                 tags: ~[(~"env", ~"prod"), (~"canary", ~"")], timestamp: None}),
+            ("foo:1|g|#a:b|T1700000000", Metric {kind: metric::Gauge, name: ~"foo", value: 1.0, member: None,
+                                                 tags: ~[(~"a", ~"b")], timestamp: Some(1700000000)})
         ];
 
         for (input, expected) in in_out_map.move_iter() {
@@ -34,18 +59,1674 @@ mod metric {
     #[test]
     fn test_from_str_invalid_input() {
         let inputs = ~[
-            "f",
-            "f:",
-            "f:c",
-            "f:1.0|",
-            "f:1.0|c@",
-            ":|@",
-            ":1.0|c"
+            ("f",              metric::MissingValue),
+            ("f:",             metric::MissingValue),
+            ("f:c",            metric::BadValue),
+            ("f:1.0|",         metric::MissingType),
+            ("f:1.0",          metric::MissingType),
+            ("f:1.0|c@",       metric::UnknownType),
+            (":|@",            metric::MissingName),
+            (":1.0|c",         metric::MissingName),
+            ("f:1.0|x",        metric::UnknownType),
+            ("f:1.0|g|@0.5",   metric::UnsampledType),
+            ("f:1.0|s|@0.5",   metric::UnsampledType),
+            ("f:1.0|c|@",      metric::BadSampleRate),
+            ("f:1.0|c|@0",     metric::BadSampleRate),
+            ("f:1.0|c|@x",     metric::BadSampleRate),
+            ("f:1.0|c|#",      metric::BadTags),
+            ("f:1.0|c|#a,,b",  metric::BadTags),
+            ("f:1.0|c|#a:",    metric::BadTags),
+            ("f:1.0|c|#:b",    metric::BadTags),
+            ("f:1.0|c|#a:b,",  metric::BadTags),
+            ("f:1.0|c|x",      metric::UnknownSection),
+            ("f:1.0|c|T",      metric::BadTimestamp),
+            ("f:1.0|c|Tnow",   metric::BadTimestamp),
+            ("f:+|g",          metric::BadValue)
         ];
 
-        for input in inputs.move_iter() {
+        for (input, expected) in inputs.move_iter() {
             let metric: Option<Metric> = FromStr::from_str(input);
             assert!(metric.is_none());
+
+            assert_eq!(Metric::parse(input), Err(expected));
+        }
+    }
+
+
+    #[test]
+    fn test_metric_ref_borrows_line() {
+        let line = "foo:1|c|@0.5|#b:c,a:d";
+        let metric = MetricRef::parse(line).unwrap();
+
+        assert_eq!(metric.name, "foo");
+        assert_eq!(metric.tags.iter().collect::<~[(&str, &str)]>(), ~[("b", "c"), ("a", "d")]);
+        assert_eq!(metric.to_metric(), Metric::parse(line).unwrap());
+        assert!(metric.to_metric().as_ref() == metric);
+    }
+
+
+    #[test]
+    fn test_parse_bytes_invalid_utf8() {
+        assert_eq!(Metric::parse_bytes([0xff, ':' as u8, '1' as u8]),
+                   Err(metric::InvalidUtf8));
+    }
+
+
+    #[test]
+    fn test_encode() {
+        let sampled = Metric {kind: metric::Counter(0.5), name: ~"foo", value: 2.0, member: None,
+                              tags: ~[(~"a", ~"b"), (~"c", ~"d")], timestamp: Some(1700000000)};
+
+        assert_eq!(sampled.encode(), ~"foo:2|c|@0.5|#a:b,c:d|T1700000000");
+        assert_eq!(Metric::new(metric::GaugeDelta, "foo", 3.0).encode(), ~"foo:+3|g");
+        assert_eq!(Metric::new(metric::GaugeDelta, "foo", -3.0).encode(), ~"foo:-3|g");
+        assert_eq!(Metric::new(metric::Gauge, "foo", -3.0).encode(), ~"foo:0|g\nfoo:-3|g");
+
+        let mut unsafe_chars = Metric::new(metric::Counter(1.0), "a:b|c\nd", 1.0);
+        unsafe_chars.tags.push((~"k:e,y", ~"v:a,l|ue"));
+        assert_eq!(unsafe_chars.encode(), ~"a_b_c_d:1|c|#k_e_y:v:a_l_ue");
+        assert_eq!(Metric::new_set("s", "a|b\nc").encode(), ~"s:a_b_c|s");
+
+        let mut bare = Metric::new(metric::Counter(1.0), "foo", 1.0);
+        bare.tags.push((~"env", ~"prod"));
+        bare.tags.push((~"canary", ~""));
+        assert_eq!(bare.encode(), ~"foo:1|c|#env:prod,canary");
+        assert_eq!(Metric::parse(bare.encode().as_slice()).unwrap(), bare);
+    }
+
+
+    /// Characters that have a meaning in the wire format, and so have to be
+    /// replaced when they show up in the wrong place.
+    static SPECIAL_CHARS: &'static str = ":|,#@\n";
+
+
+    /// Generate a random string of up to `max_len` characters, some of them
+    /// special.
+    fn random_str<R: Rng>(rng: &mut R, max_len: uint) -> ~str {
+        let len = rng.gen_range(1u, max_len);
+
+        range(0, len).map(|_| {
+            if rng.gen_weighted_bool(8) {
+                SPECIAL_CHARS.char_at(rng.gen_range(0u, SPECIAL_CHARS.len()))
+            } else {
+                rng.gen_ascii_str(1).char_at(0)
+            }
+        }).collect()
+    }
+
+
+    /// `s` with each of the `reserved` characters replaced by `_`.
+    fn replaced(s: &str, reserved: &[char]) -> ~str {
+        s.chars().map(|c| if reserved.contains(&c) { '_' } else { c }).collect()
+    }
+
+
+    /// Generate a random metric of any kind that the wire format can express
+    /// as a single line.
+    fn random_metric<R: Rng>(rng: &mut R) -> Metric {
+        let rates = [1.0, 0.5, 0.25, 0.125];
+        let rate = rates[rng.gen_range(0u, rates.len())];
+
+        // Multiples of 1/8 survive formatting without losing precision.
+        let value = rng.gen_range(-100000i, 100000) as f64 / 8.0;
+
+        let (kind, value, member) = match rng.gen_range(0u, 8) {
+            0 => (metric::Counter(rate), value, None),
+            1 => (metric::Gauge, value.abs(), None),
+            2 => (metric::GaugeDelta, value, None),
+            3 => (metric::Timer(rate), value.abs(), None),
+            4 => (metric::Histogram(rate), value, None),
+            5 => (metric::Distribution(rate), value, None),
+            _ => (metric::Set, 0.0, Some(random_str(rng, 20)))
+        };
+
+        let tags = range(0, rng.gen_range(0u, 4)).map(|_| {
+            (random_str(rng, 10), random_str(rng, 10))
+        }).collect();
+
+        let timestamp = if rng.gen() { Some(rng.gen_range(0i64, 2000000000)) } else { None };
+
+        Metric {kind: kind, name: random_str(rng, 20), value: value, member: member,
+                tags: tags, timestamp: timestamp}
+    }
+
+
+    /// The metric that `metric` should be parsed back as once encoded.
+    fn sanitized(metric: &Metric) -> Metric {
+        Metric {
+            kind: metric.kind,
+            name: replaced(metric.name.as_slice(), [':', '|', '\n']),
+            value: metric.value,
+            member: metric.member.as_ref().map(|member| replaced(member.as_slice(), ['|', '\n'])),
+            tags: metric.tags.iter().map(|&(ref key, ref value)| {
+                (replaced(key.as_slice(), [':', ',', '|', '\n']),
+                 replaced(value.as_slice(), [',', '|', '\n']))
+            }).collect(),
+            timestamp: metric.timestamp
+        }
+    }
+
+
+    #[test]
+    fn test_encode_parse_round_trip() {
+        let mut rng = task_rng();
+
+        for _ in range(0, 10000) {
+            let metric = random_metric(&mut rng);
+            let line = metric.encode();
+
+            assert_eq!(Metric::parse(line), Ok(sanitized(&metric)));
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod event {
+    use statsd::metric;
+    use statsd::event;
+    use statsd::event::{Event, ServiceCheck};
+
+    #[test]
+    fn test_parse_event() {
+        let event = Event::parse("_e{5,11}:hello|line\\nbreak|d:1700000000|h:web1|t:error|#env:prod").unwrap();
+
+        assert_eq!(event.title, ~"hello");
+        assert_eq!(event.text, ~"line\nbreak");
+        assert_eq!(event.timestamp, Some(1700000000));
+        assert_eq!(event.hostname, Some(~"web1"));
+        assert_eq!(event.alert_type, Some(~"error"));
+        assert_eq!(event.tags, ~[(~"env", ~"prod")]);
+
+        let minimal = Event::parse("_e{1,0}:a|").unwrap();
+        assert_eq!(minimal.title, ~"a");
+        assert_eq!(minimal.text, ~"");
+    }
+
+    #[test]
+    fn test_parse_event_invalid() {
+        let inputs = ~[
+            "_e{5,5}:hello|worl",
+            "_e{5,5}:hello|worldx",
+            "_e{5}:hello|world",
+            "_e{a,5}:hello|world",
+            "_e{0,5}:|world",
+            "_e{5,5}hello|world",
+            "_e{5,5}:hello|world|x:1",
+            "_e{5,5}:hello|world|d:soon",
+            "_e{1,18446744073709551615}:a|b",
+            "_e{18446744073709551615,1}:a|b"
+        ];
+
+        for input in inputs.move_iter() {
+            assert_eq!(Event::parse(input), Err(metric::BadEvent));
+        }
+    }
+
+    #[test]
+    fn test_parse_service_check() {
+        let check = ServiceCheck::parse("_sc|db.up|2|d:1700000000|h:db1|#env:prod|m:down | hard").unwrap();
+
+        assert_eq!(check.name, ~"db.up");
+        assert_eq!(check.status, event::StatusCritical);
+        assert_eq!(check.timestamp, Some(1700000000));
+        assert_eq!(check.hostname, Some(~"db1"));
+        assert_eq!(check.tags, ~[(~"env", ~"prod")]);
+        assert_eq!(check.message, Some(~"down | hard"));
+
+        let inputs = ~["_sc|db.up", "_sc||0", "_sc|db.up|4", "_sc|db.up|0|x:1"];
+
+        for input in inputs.move_iter() {
+            assert_eq!(ServiceCheck::parse(input), Err(metric::BadServiceCheck));
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod buckets {
+    use statsd::metric;
+    use statsd::metric::{Key, Metric};
+    use statsd::server::buckets::Buckets;
+
+    use time;
+
+    fn key(name: &str) -> Key {
+        Key::new(name.to_owned(), ~[])
+    }
+
+    #[test]
+    fn test_sets_count_unique_values() {
+        let mut buckets = Buckets::new();
+
+        // The last two IDs are 2^54 + 1 and 2^54, which are the same float.
+        let members = ["42", "ab12", "42", "42", "18014398509481985", "18014398509481984"];
+        for member in members.iter() {
+            buckets.add_metric(Metric::new_set("users", *member));
+        }
+
+        {
+            let users = buckets.sets.get(&key("users"));
+            assert_eq!(users.len(), 4);
+            assert!(users.contains(&~"ab12") && users.contains(&~"18014398509481985"));
+        }
+
+        buckets.flush();
+        assert!(buckets.sets.is_empty());
+    }
+
+    #[test]
+    fn test_add_packet_multiple_lines() {
+        let mut buckets = Buckets::new();
+
+        buckets.add_packet(bytes!("a:1|g\nb:2|s\nnope\nc:3|x\nd:4|g\n"));
+
+        assert_eq!(buckets.total_messages, 3);
+        assert_eq!(buckets.bad_messages, 2);
+        assert_eq!(*buckets.gauges.get(&key("a")), 1.0);
+        assert_eq!(*buckets.gauges.get(&key("d")), 4.0);
+        assert_eq!(buckets.sets.get(&key("b")).len(), 1);
+    }
+
+    #[test]
+    fn test_add_packet_invalid_utf8_line() {
+        let mut buckets = Buckets::new();
+
+        buckets.add_packet([0xff, 0xfe, '\n' as u8, 'a' as u8, ':' as u8,
+                            '1' as u8, '|' as u8, 'g' as u8]);
+
+        assert_eq!(buckets.total_messages, 1);
+        assert_eq!(buckets.bad_messages, 1);
+    }
+
+    #[test]
+    fn test_tagged_series_are_separate() {
+        let mut buckets = Buckets::new();
+
+        buckets.add_packet(bytes!("a:1|c|#env:prod,host:a\na:2|c|#host:a,env:prod\na:4|c\na:8|c|#env:dev"));
+
+        let prod = Key::new(~"a", ~[(~"env", ~"prod"), (~"host", ~"a")]);
+        let dev = Key::new(~"a", ~[(~"env", ~"dev")]);
+
+        assert_eq!(buckets.counters.len(), 3);
+        assert_eq!(*buckets.counters.get(&prod), 3.0);
+        assert_eq!(*buckets.counters.get(&dev), 8.0);
+        assert_eq!(*buckets.counters.get(&key("a")), 4.0);
+    }
+
+    #[test]
+    fn test_relative_gauges() {
+        let mut buckets = Buckets::new();
+
+        buckets.add_packet(bytes!("a:10|g\nb:-2|g"));
+        buckets.flush();
+        buckets.add_packet(bytes!("a:+5|g\na:-3|g\nb:+1|g"));
+
+        assert_eq!(*buckets.gauges.get(&key("a")), 12.0);
+        assert_eq!(*buckets.gauges.get(&key("b")), -1.0);
+
+        buckets.add_packet(bytes!("a:1|g"));
+        assert_eq!(*buckets.gauges.get(&key("a")), 1.0);
+    }
+
+    #[test]
+    fn test_sampled_timer_counts() {
+        let mut buckets = Buckets::new();
+
+        buckets.add_packet(bytes!("t:12|ms|@0.1\nt:20|ms\nh:1|h|@0.5\nh:2|h|@0.5\nh:3|d|@0.5"));
+
+        assert_eq!(buckets.timers.get(&key("t")).len(), 2);
+        assert_eq!(*buckets.timer_counts.get(&key("t")), 11.0);
+        assert_eq!(*buckets.histogram_counts.get(&key("h")), 6.0);
+        assert_eq!(buckets.histograms.get(&key("h")).len(), 3);
+
+        buckets.flush();
+        assert!(buckets.timer_counts.is_empty());
+        assert!(buckets.histogram_counts.is_empty());
+    }
+
+    #[test]
+    fn test_parse_errors_by_kind() {
+        let mut buckets = Buckets::new();
+
+        buckets.add_packet(bytes!("a:1|x\nb:2|y\n:3|c\nc:1|c"));
+
+        assert_eq!(buckets.bad_messages, 3);
+        assert_eq!(*buckets.parse_errors.get(&metric::UnknownType), 2);
+        assert_eq!(*buckets.parse_errors.get(&metric::MissingName), 1);
+
+        let (stats, _) = buckets.do_management_line("stats");
+        assert!(stats.contains("unknown type: 2"));
+        assert!(stats.contains("missing name: 1"));
+    }
+
+    #[test]
+    fn test_events_and_service_checks() {
+        let mut buckets = Buckets::new();
+
+        buckets.add_packet(bytes!("_e{2,3}:up|yes\n_sc|db|0\n_sc|db|2|m:oh no\na:1|c\n_e{9,1}:x|y"));
+
+        assert_eq!(buckets.events.len(), 1);
+        assert_eq!(buckets.service_checks.len(), 2);
+        assert_eq!(buckets.total_messages, 4);
+        assert_eq!(*buckets.parse_errors.get(&metric::BadEvent), 1);
+
+        buckets.flush();
+        assert!(buckets.events.is_empty());
+        assert!(buckets.service_checks.is_empty());
+
+        let (health, _) = buckets.do_management_line("health");
+        assert!(health.contains("db: CRITICAL - oh no"));
+    }
+
+    #[test]
+    fn test_timestamped_samples() {
+        let mut buckets = Buckets::new();
+
+        buckets.add_packet(bytes!("a:1|c|T1700000000\na:2|c"));
+        assert_eq!(*buckets.counters.get(&key("a")), 3.0);
+        assert!(buckets.timestamped.is_empty());
+
+        buckets.flush();
+        buckets.keep_timestamps = true;
+        buckets.add_packet(bytes!("a:1|c|T1700000000\na:2|c"));
+
+        assert_eq!(*buckets.counters.get(&key("a")), 2.0);
+        assert_eq!(buckets.timestamped.len(), 1);
+        assert_eq!(buckets.timestamped[0].timestamp, Some(1700000000));
+
+        // Gauge deltas and sets can't be exported as points.
+        buckets.add_packet(bytes!("g:5|g\ng:+2|g|T1700000000\ns:x|s|T1700000000"));
+        assert_eq!(*buckets.gauges.get(&key("g")), 7.0);
+        assert_eq!(buckets.sets.get(&key("s")).len(), 1);
+        assert_eq!(buckets.timestamped.len(), 1);
+    }
+
+    #[test]
+    fn test_stale_samples_dropped() {
+        let mut buckets = Buckets::new();
+        buckets.max_sample_age = Some(60);
+
+        let now = time::get_time().sec;
+        let packet = format!("a:1|c|T{}\na:2|c|T{}\na:4|c", now - 3600, now - 10);
+        buckets.add_packet(packet.as_bytes());
+
+        assert_eq!(*buckets.counters.get(&key("a")), 6.0);
+        assert_eq!(buckets.stale_messages, 1);
+        assert_eq!(buckets.total_messages, 2);
+    }
+}
+
+
+#[cfg(test)]
+mod graphite {
+    use statsd::metric::Metric;
+    use statsd::server::backends::graphite::Graphite;
+
+    use std::from_str::FromStr;
+
+    #[test]
+    fn test_fmt_timestamped() {
+        let graphite = Graphite::new(FromStr::from_str("127.0.0.1:2003").unwrap());
+        let samples: ~[Metric] = ["a:1|c|@0.5|T100", "a:2|c|T100", "a:1|c|T200",
+                                  "t:12|ms|@0.1|T100", "g:3|g|T100", "b:1|c", "c:1|c|#canary|T100"]
+            .iter().map(|line| Metric::parse(*line).unwrap()).collect();
+
+        let out = graphite.fmt_timestamped(samples, 300);
+        let mut lines: ~[&str] = out.lines().collect();
+        lines.sort();
+
+        assert_eq!(lines, ~["counters.a 1 200", "counters.a 4 100", "counters.b 1 300",
+                            "counters.c;canary=true 1 100", "gauges.g 3 100", "timers.t 12 100"]);
+    }
+}
+
+
+#[cfg(test)]
+mod client {
+    use statsd::client::Client;
+    use statsd::sink::MetricSink;
+    use statsd::metric;
+    use statsd::metric::Metric;
+
+    use std::from_str::FromStr;
+    use std::io::net::ip::{Ipv4Addr, SocketAddr};
+    use std::io::net::udp::UdpSocket;
+    use std::io::timer::sleep;
+    use std::str;
+
+    /// Bind a socket on an unused local port to act as the statsd server.
+    pub fn server() -> (UdpSocket, SocketAddr) {
+        let addr: SocketAddr = FromStr::from_str("127.0.0.1:0").unwrap();
+        let mut sock = UdpSocket::bind(addr).unwrap();
+        let addr = sock.socket_name().unwrap();
+
+        (sock, addr)
+    }
+
+    /// Receive a single packet sent to the server.
+    pub fn recv_packet(sock: &mut UdpSocket) -> ~str {
+        let mut buf = [0u8, ..1024];
+        let (nread, _) = sock.recvfrom(buf).unwrap();
+
+        str::from_utf8(buf.slice_to(nread)).unwrap().to_owned()
+    }
+
+    #[test]
+    fn test_prefix_and_default_tags() {
+        let (mut sock, addr) = server();
+        let mut client = Client::new_with_prefix("svc.prod", addr).unwrap();
+        client.add_default_tag("host", "a");
+
+        client.incr("hits", 1.0);
+        assert_eq!(recv_packet(&mut sock), ~"svc.prod.hits:1|c|#host:a");
+
+        client.hist("size", 3.0);
+        let metric: Metric = FromStr::from_str(recv_packet(&mut sock)).unwrap();
+        assert_eq!(metric.name, ~"svc.prod.size");
+        assert_eq!(metric.kind, metric::Histogram(1.0));
+        assert_eq!(metric.tags, ~[(~"host", ~"a")]);
+
+        client.time_block("block", proc() {});
+        let metric: Metric = FromStr::from_str(recv_packet(&mut sock)).unwrap();
+        assert_eq!(metric.name, ~"svc.prod.block");
+        assert_eq!(metric.tags, ~[(~"host", ~"a")]);
+    }
+
+    #[test]
+    fn test_empty_prefix_is_ignored() {
+        let (mut sock, addr) = server();
+        let mut client = Client::new_with_prefix("", addr).unwrap();
+
+        client.incr("hits", 1.0);
+        assert_eq!(recv_packet(&mut sock), ~"hits:1|c");
+
+        client.set_prefix("svc");
+        client.set_prefix("");
+        client.incr("hits", 1.0);
+        assert_eq!(recv_packet(&mut sock), ~"hits:1|c");
+    }
+
+    #[test]
+    fn test_buffered_packs_lines() {
+        let (mut sock, addr) = server();
+        let mut client = Client::new_buffered(addr, 32, 60000).unwrap();
+
+        // Each line is 12 bytes, so only two fit into a 32 byte packet.
+        for _ in range(0, 4) {
+            client.gauge("gauge", 1234.0);
+        }
+        assert_eq!(recv_packet(&mut sock), ~"gauge:1234|g\ngauge:1234|g");
+
+        client.flush();
+        assert_eq!(recv_packet(&mut sock), ~"gauge:1234|g\ngauge:1234|g");
+
+        client.incr("a", 1.0);
+        drop(client);
+        assert_eq!(recv_packet(&mut sock), ~"a:1|c");
+    }
+
+    #[test]
+    fn test_buffered_max_latency() {
+        let (mut sock, addr) = server();
+        let mut client = Client::new_buffered(addr, 512, 0).unwrap();
+
+        client.incr("a", 1.0);
+        assert_eq!(recv_packet(&mut sock), ~"a:1|c");
+
+        // The latency is only checked when a metric is sent, or when asked.
+        client.set_buffered(512, 50);
+        client.incr("a", 1.0);
+        assert!(client.ms_until_due().unwrap() <= 50);
+        sleep(60);
+        client.incr("b", 1.0);
+        assert_eq!(recv_packet(&mut sock), ~"a:1|c\nb:1|c");
+        assert_eq!(client.ms_until_due(), None);
+
+        client.incr("c", 1.0);
+        client.flush_if_due();
+        sleep(60);
+        assert_eq!(client.ms_until_due(), Some(0));
+        client.flush_if_due();
+        assert_eq!(recv_packet(&mut sock), ~"c:1|c");
+    }
+
+    #[test]
+    fn test_send_errors_are_counted() {
+        // Sending to the broadcast address without asking for it fails.
+        let dest = SocketAddr { ip: Ipv4Addr(255, 255, 255, 255), port: 8125 };
+        let mut client = Client::new(dest).unwrap();
+
+        assert_eq!(client.send_errors(), 0);
+
+        client.incr("a", 1.0);
+        client.incr("a", 1.0);
+
+        assert_eq!(client.send_errors(), 2);
+        assert!(client.take_last_error().is_some());
+        assert!(client.take_last_error().is_none());
+    }
+
+    #[test]
+    fn test_sets_distributions_and_gauge_deltas() {
+        let (mut sock, addr) = server();
+        let mut client = Client::new(addr).unwrap();
+
+        let expected = [
+            ("s:user-7|s", Metric::new_set("s", "user-7")),
+            ("d:2.5|d", Metric::new(metric::Distribution(1.0), "d", 2.5)),
+            ("g:+3|g", Metric::new(metric::GaugeDelta, "g", 3.0)),
+            ("g:-4|g", Metric::new(metric::GaugeDelta, "g", -4.0))
+        ];
+
+        client.set("s", "user-7");
+        client.distribution("d", 2.5, 1.0);
+        client.gauge_incr("g", 3.0);
+        client.gauge_decr("g", 4.0);
+
+        for &(ref line, ref metric) in expected.iter() {
+            let packet = recv_packet(&mut sock);
+            assert_eq!(packet.as_slice(), *line);
+
+            let parsed: Metric = FromStr::from_str(packet).unwrap();
+            assert_eq!(parsed, *metric);
+        }
+    }
+
+    /// Check that a timing of a 50ms sleep was reported in milliseconds.
+    fn assert_timed_sleep(sock: &mut UdpSocket, name: &str) {
+        let metric: Metric = FromStr::from_str(recv_packet(sock)).unwrap();
+        assert_eq!(metric.name.as_slice(), name);
+        assert_eq!(metric.kind, metric::Timer(1.0));
+
+        // In microseconds this would be at least 50000.
+        assert!(metric.value >= 50.0 && metric.value < 5000.0);
+    }
+
+    #[test]
+    fn test_time_fn_returns_result() {
+        let (mut sock, addr) = server();
+        let mut client = Client::new(addr).unwrap();
+
+        let result = client.time_fn("fn", || { sleep(50); 42 });
+        assert_eq!(result, 42);
+        assert_timed_sleep(&mut sock, "fn");
+
+        client.time_block("block", proc() { sleep(50) });
+        assert_timed_sleep(&mut sock, "block");
+    }
+
+    #[test]
+    fn test_running_timer() {
+        let (mut sock, addr) = server();
+        let mut client = Client::new(addr).unwrap();
+
+        let timer = client.start_timer("stopped");
+        sleep(50);
+
+        // The client can still be used while the timer runs.
+        client.incr("during", 1.0);
+        assert_eq!(recv_packet(&mut sock), ~"during:1|c");
+
+        let elapsed = timer.stop(&mut client);
+        assert!(elapsed >= 50 && elapsed < 5000);
+        assert_timed_sleep(&mut sock, "stopped");
+    }
+}
+
+
+#[cfg(test)]
+mod sink {
+    use statsd::metric;
+    use statsd::metric::Metric;
+    use statsd::sink::{MetricSink, CaptureSink, NoopSink};
+
+    /// Stands in for application code that only knows about the trait.
+    fn handle_request<S: MetricSink>(sink: &mut S) {
+        sink.incr("requests", 0.5);
+        sink.gauge("queue", 3.0);
+        sink.time("latency", 12);
+    }
+
+    #[test]
+    fn test_capture_sink() {
+        let mut sink = CaptureSink::new();
+        handle_request(&mut sink);
+
+        let metrics = sink.take();
+        assert_eq!(metrics, ~[
+            Metric::new(metric::Counter(0.5), "requests", 1.0),
+            Metric::new(metric::Gauge, "queue", 3.0),
+            Metric::new(metric::Timer(1.0), "latency", 12.0)
+        ]);
+        assert!(sink.metrics.is_empty());
+    }
+
+    #[test]
+    fn test_timers_report_through_any_sink() {
+        let mut sink = CaptureSink::new();
+        assert_eq!(sink.time_fn("fn", || 42), 42);
+
+        // Started on one sink, sent through another.
+        let timer = NoopSink.start_timer("timer");
+        let elapsed = timer.stop(&mut sink);
+
+        let metrics = sink.take();
+        assert_eq!(metrics.len(), 2);
+        assert_eq!(metrics[0].name, ~"fn");
+        assert_eq!(metrics[0].kind, metric::Timer(1.0));
+        assert_eq!(metrics[1], Metric::new(metric::Timer(1.0), "timer", elapsed as f64));
+    }
+
+    #[test]
+    fn test_trait_objects() {
+        let mut sinks = ~[box NoopSink as ~MetricSink, box CaptureSink::new() as ~MetricSink];
+
+        for sink in sinks.mut_iter() {
+            sink.decr("a", 1.0);
+            sink.hist("b", 2.0);
+            sink.flush();
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod sampler {
+    use statsd::client::Client;
+    use statsd::metric::Key;
+    use statsd::sampler::{Sampler, RandomSampler, AlwaysSample, NeverSample};
+    use statsd::server::buckets::Buckets;
+    use statsd::sink::MetricSink;
+    use statsd::transport::Transport;
+
+    use std::comm::{Sender, channel};
+    use std::io::IoResult;
+
+    /// Passes every packet back to the test.
+    pub struct ChannelTransport {
+        pub packets: Sender<~[u8]>
+    }
+
+    impl Transport for ChannelTransport {
+        fn send(&mut self, data: &[u8]) -> IoResult<()> {
+            self.packets.send(data.to_owned());
+            Ok(())
+        }
+    }
+
+    /// Number of `draws` samples at `rate` that `sampler` lets through.
+    fn count_sampled<S: Sampler>(sampler: &mut S, rate: f64, draws: uint) -> uint {
+        let mut sent = 0;
+
+        for _ in range(0, draws) {
+            if sampler.sample(rate) {
+                sent += 1;
+            }
         }
+
+        sent
+    }
+
+    #[test]
+    fn test_random_sampler_rate() {
+        let mut sampler = RandomSampler::new_seeded([1, 2, 3, 4]);
+
+        // The standard deviation of each count is under 150, so these
+        // bounds are more than five of them away.
+        for &rate in [0.01, 0.1, 0.5, 0.9].iter() {
+            let expected = rate * 100000.0;
+            let sent = count_sampled(&mut sampler, rate, 100000) as f64;
+            assert!((sent - expected).abs() < 750.0);
+        }
+
+        assert_eq!(count_sampled(&mut sampler, 1.0, 1000), 1000);
+        assert_eq!(count_sampled(&mut sampler, 0.0, 1000), 0);
+    }
+
+    #[test]
+    fn test_seeded_sampler_is_deterministic() {
+        let mut a = RandomSampler::new_seeded([5, 6, 7, 8]);
+        let mut b = RandomSampler::new_seeded([5, 6, 7, 8]);
+
+        for _ in range(0, 1000) {
+            assert_eq!(a.sample(0.5), b.sample(0.5));
+        }
+    }
+
+    #[test]
+    fn test_always_and_never() {
+        assert_eq!(count_sampled(&mut AlwaysSample, 0.01, 100), 100);
+        assert_eq!(count_sampled(&mut NeverSample, 0.99, 100), 0);
+        assert_eq!(count_sampled(&mut NeverSample, 1.0, 100), 100);
+    }
+
+    #[test]
+    fn test_sampled_counter_total() {
+        let (packets_send, packets_recv) = channel();
+
+        let transport = ChannelTransport { packets: packets_send };
+        let mut client = Client::new_with_transport(box transport as ~Transport);
+        client.set_sampler(box RandomSampler::new_seeded([9, 10, 11, 12]) as ~Sampler);
+
+        for _ in range(0, 10000) {
+            client.incr("a", 0.1);
+        }
+        drop(client);
+
+        let mut buckets = Buckets::new();
+        for packet in packets_recv.iter() {
+            buckets.add_packet(packet);
+        }
+
+        // Each sample is scaled up by the server, so the total should be
+        // close to the number of increments.
+        let total = *buckets.counters.get(&Key::new(~"a", ~[]));
+        assert!(total > 8500.0 && total < 11500.0);
+    }
+}
+
+
+#[cfg(test)]
+mod aggregate {
+    use statsd::aggregate::Aggregator;
+    use statsd::client::Client;
+    use statsd::metric;
+    use statsd::metric::{Key, Metric};
+    use statsd::sampler::{Sampler, AlwaysSample};
+    use statsd::server::buckets::Buckets;
+    use statsd::sink::MetricSink;
+    use statsd::transport::Transport;
+
+    use sampler::ChannelTransport;
+
+    use std::comm::channel;
+
+    fn key(name: &str) -> Key {
+        Key::new(name.to_owned(), ~[])
+    }
+
+    /// Send every metric in `metrics` through an aggregating client, and
+    /// return the packets it sent.
+    fn send_aggregated(metrics: ~[Metric]) -> ~[~[u8]] {
+        let (packets_send, packets_recv) = channel();
+
+        let transport = ChannelTransport { packets: packets_send };
+        let mut client = Client::new_with_transport(box transport as ~Transport);
+        client.set_sampler(box AlwaysSample as ~Sampler);
+        client.set_aggregated(60000, 100);
+
+        for metric in metrics.move_iter() {
+            client.send_metric(metric);
+        }
+        drop(client);
+
+        packets_recv.iter().collect()
+    }
+
+    #[test]
+    fn test_same_buckets() {
+        let mut metrics = ~[];
+        for i in range(0, 500) {
+            metrics.push(Metric::new(metric::Counter(1.0), "c", 1.0));
+            metrics.push(Metric::new(metric::Counter(0.5), "c", 2.0));
+            metrics.push(Metric::new(metric::Gauge, "g", i as f64));
+            metrics.push(Metric::new(metric::GaugeDelta, "d", -1.0));
+            metrics.push(Metric::new_set("s", format!("user-{}", i % 7).as_slice()));
+        }
+        metrics.push(Metric::new(metric::GaugeDelta, "g", 5.0));
+        metrics.push(Metric::new(metric::Timer(0.5), "t", 12.0));
+        metrics.push(Metric::new(metric::Timer(1.0), "t", 20.0));
+
+        let mut direct = Buckets::new();
+        for metric in metrics.iter() {
+            direct.add_metric_ref(&metric.as_ref());
+        }
+
+        let packets = send_aggregated(metrics);
+        assert!(packets.len() <= 12);
+
+        let mut aggregated = Buckets::new();
+        for packet in packets.iter() {
+            aggregated.add_packet(packet.as_slice());
+        }
+
+        assert_eq!(aggregated.counters.get(&key("c")), direct.counters.get(&key("c")));
+        assert_eq!(aggregated.gauges.get(&key("g")), direct.gauges.get(&key("g")));
+        assert_eq!(aggregated.gauges.get(&key("d")), direct.gauges.get(&key("d")));
+        assert_eq!(aggregated.timer_counts.get(&key("t")), direct.timer_counts.get(&key("t")));
+        assert!(aggregated.sets.get(&key("s")) == direct.sets.get(&key("s")));
+
+        let timers = aggregated.timers.get(&key("t"));
+        assert_eq!(timers.len(), 2);
+        assert!(timers.contains(&12.0) && timers.contains(&20.0));
+    }
+
+    #[test]
+    fn test_samples_are_capped() {
+        let mut aggregator = Aggregator::new(60000, 2);
+
+        for _ in range(0, 5) {
+            assert!(aggregator.add(Metric::new(metric::Histogram(1.0), "h", 1.0)).is_none());
+        }
+
+        let stamped = Metric { timestamp: Some(1700000000), ..Metric::new(metric::Gauge, "g", 1.0) };
+        assert_eq!(aggregator.add(stamped).map(|m| m.timestamp), Some(Some(1700000000)));
+
+        assert_eq!(aggregator.drain().len(), 2);
+        assert_eq!(aggregator.dropped_samples(), 3);
+        assert!(aggregator.drain().is_empty());
+    }
+
+    #[test]
+    fn test_full_buffer_keeps_aggregates() {
+        let (packets_send, packets_recv) = channel();
+
+        let transport = ChannelTransport { packets: packets_send };
+        let mut client = Client::new_with_transport(box transport as ~Transport);
+        client.set_buffered(32, 60000);
+        client.set_aggregated(60000, 100);
+
+        // Timestamped metrics skip the aggregator and fill up the buffer,
+        // which mustn't send the aggregated counter early.
+        client.incr("c", 1.0);
+        for _ in range(0, 3) {
+            client.send_metric(Metric { timestamp: Some(1700000000), ..Metric::new(metric::Gauge, "g", 1.0) });
+        }
+        client.incr("c", 1.0);
+        drop(client);
+
+        let packets: ~[~[u8]] = packets_recv.iter().collect();
+        assert_eq!(packets, ~[bytes!("g:1|g|T1700000000").to_owned(),
+                              bytes!("g:1|g|T1700000000").to_owned(),
+                              bytes!("g:1|g|T1700000000\nc:2|c").to_owned()]);
+    }
+}
+
+
+#[cfg(test)]
+mod builder {
+    use statsd::builder;
+    use statsd::builder::ClientBuilder;
+    use statsd::sampler::{Sampler, NeverSample};
+    use statsd::sink::MetricSink;
+
+    use std::from_str::FromStr;
+    use std::io::net::ip::SocketAddr;
+    use std::io::net::udp::UdpSocket;
+
+    use client::{server, recv_packet};
+
+    /// Look variables up in `vars` instead of the environment.
+    fn from_vars(vars: &[(&str, &str)]) -> Result<ClientBuilder, builder::ConfigError> {
+        ClientBuilder::from_vars(|name| {
+            vars.iter().find(|&&(key, _)| key == name).map(|&(_, value)| value.to_owned())
+        })
+    }
+
+    #[test]
+    fn test_from_vars() {
+        let (mut sock, addr) = server();
+        let port = addr.port.to_str();
+
+        let vars = [("STATSD_HOST", "127.0.0.1"),
+                    ("STATSD_PORT", port.as_slice()),
+                    ("STATSD_PREFIX", "svc")];
+        let mut client = from_vars(vars).unwrap()
+            .default_tag("env", "test")
+            .build().unwrap();
+
+        client.incr("hits", 1.0);
+        assert_eq!(recv_packet(&mut sock), ~"svc.hits:1|c|#env:test");
+    }
+
+    #[test]
+    fn test_ipv6_destination() {
+        let addr: SocketAddr = FromStr::from_str("[::1]:0").unwrap();
+        let mut sock = UdpSocket::bind(addr).unwrap();
+        let port = sock.socket_name().unwrap().port.to_str();
+
+        let vars = [("STATSD_HOST", "::1"), ("STATSD_PORT", port.as_slice())];
+        let mut client = from_vars(vars).unwrap().build().unwrap();
+
+        client.incr("hits", 1.0);
+        assert_eq!(recv_packet(&mut sock), ~"hits:1|c");
+        assert_eq!(client.send_errors(), 0);
+    }
+
+    #[test]
+    fn test_bad_values() {
+        match from_vars([("STATSD_PORT", "81250")]) {
+            Err(builder::BadPort(port)) => assert_eq!(port, ~"81250"),
+            _ => fail!("port out of range was accepted")
+        }
+
+        match ClientBuilder::new().sample_rate(0.0).build() {
+            Err(builder::BadSampleRate(rate)) => assert_eq!(rate, 0.0),
+            _ => fail!("zero sample rate was accepted")
+        }
+
+        match ClientBuilder::new().host("no-such-host.invalid").build() {
+            Err(builder::UnknownHost(host)) => assert_eq!(host, ~"no-such-host.invalid"),
+            _ => fail!("unknown host was resolved")
+        }
+    }
+
+    #[test]
+    fn test_default_sample_rate() {
+        let (mut sock, addr) = server();
+
+        let mut client = ClientBuilder::new()
+            .host("127.0.0.1")
+            .port(addr.port)
+            .sample_rate(0.5)
+            .sampler(box NeverSample as ~Sampler)
+            .build().unwrap();
+
+        // Counts without a rate of their own are now sampled, and never sent,
+        // but gauges and counters explicitly sent at a rate of 1 still are.
+        client.count("hits", 1.0);
+        client.time("t", 12);
+        client.gauge("g", 1.0);
+        client.incr("forced", 1.0);
+
+        assert_eq!(recv_packet(&mut sock), ~"g:1|g");
+        assert_eq!(recv_packet(&mut sock), ~"forced:1|c");
+    }
+
+    #[test]
+    fn test_resolve_needs_udp() {
+        let path = Path::new("/tmp/statsd.sock");
+
+        let builders = ~[ClientBuilder::new().tcp(), ClientBuilder::new().unix(&path)];
+
+        for config in builders.move_iter() {
+            match config.resolve_every(1000).build() {
+                Err(builder::ResolveNeedsUdp) => {},
+                _ => fail!("resolve_every was accepted without UDP")
+            }
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod background {
+    use statsd::background::BackgroundClient;
+    use statsd::client::Client;
+    use statsd::sink::MetricSink;
+    use statsd::transport::Transport;
+
+    use std::comm::{Sender, Receiver, channel};
+    use std::io::IoResult;
+    use std::str;
+
+    use client::{server, recv_packet};
+
+    /// A transport that tells the test when it starts a send, then waits to
+    /// be let through before passing the data on.
+    struct GatedTransport {
+        started: Sender<()>,
+        gate: Receiver<()>,
+        sent: Sender<~str>
+    }
+
+    impl Transport for GatedTransport {
+        fn send(&mut self, data: &[u8]) -> IoResult<()> {
+            self.started.send(());
+            self.gate.recv();
+            self.sent.send(str::from_utf8(data).unwrap().to_owned());
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_send_from_cloned_handles() {
+        let (mut sock, addr) = server();
+
+        let mut client = BackgroundClient::new(Client::new(addr).unwrap(), 16);
+        let other = client.clone();
+
+        spawn(proc() {
+            let mut other = other;
+            other.count("a", 2.0);
+        });
+
+        assert_eq!(recv_packet(&mut sock), ~"a:2|c");
+
+        client.gauge("b", 1.0);
+        assert_eq!(recv_packet(&mut sock), ~"b:1|g");
+
+        client.shutdown();
+    }
+
+    #[test]
+    fn test_buffered_metrics_sent_when_due() {
+        let (mut sock, addr) = server();
+
+        let client = Client::new_buffered(addr, 512, 20).unwrap();
+        let mut client = BackgroundClient::new(client, 16);
+
+        // Nothing else is sent, so the background task has to wake up by
+        // itself to send these.
+        client.incr("a", 1.0);
+        client.incr("b", 1.0);
+        assert_eq!(recv_packet(&mut sock), ~"a:1|c\nb:1|c");
+
+        client.shutdown();
+    }
+
+    #[test]
+    fn test_full_queue_drops_and_counts() {
+        let (started_send, started_recv) = channel();
+        let (gate_send, gate_recv) = channel();
+        let (sent_send, sent_recv) = channel();
+
+        let transport = GatedTransport {
+            started: started_send,
+            gate: gate_recv,
+            sent: sent_send
+        };
+        let client = Client::new_with_transport(box transport as ~Transport);
+        let mut client = BackgroundClient::new(client, 1);
+
+        // The first metric is held up in the transport, the second fills the
+        // queue and the rest have nowhere to go.
+        client.incr("a", 1.0);
+        started_recv.recv();
+        for _ in range(0, 3) {
+            client.incr("a", 1.0);
+        }
+        assert_eq!(client.dropped(), 2);
+
+        gate_send.send(());
+        gate_send.send(());
+        client.shutdown();
+
+        assert_eq!(sent_recv.recv(), ~"a:1|c");
+        assert_eq!(sent_recv.recv(), ~"a:1|c");
+        assert!(sent_recv.try_recv().is_err());
+    }
+}
+
+
+#[cfg(test)]
+mod shared {
+    use statsd::sampler::{Sampler, NeverSample};
+    use statsd::shared::SharedClient;
+    use statsd::sink::MetricSink;
+
+    use std::io::net::ip::{Ipv4Addr, SocketAddr};
+
+    use sync::Arc;
+
+    use client::{server, recv_packet};
+
+    fn assert_send_share<T: Send + Share>() {}
+
+    #[test]
+    fn test_shared_between_tasks() {
+        assert_send_share::<SharedClient>();
+
+        let (mut sock, addr) = server();
+
+        let mut client = SharedClient::new_with_prefix("svc", addr).unwrap();
+        client.add_default_tag("host", "a");
+        let client = Arc::new(client);
+
+        for i in range(0, 4) {
+            let client = client.clone();
+            spawn(proc() {
+                let name = format!("task{}", i);
+
+                for _ in range(0, 3) {
+                    client.count(name.as_slice(), 1.0);
+                }
+
+                // The faster path, with a sampler kept by the sink.
+                let mut sink = client.sink();
+                for _ in range(0, 2) {
+                    sink.count(name.as_slice(), 1.0);
+                }
+            });
+        }
+
+        let mut counts = [0, ..4];
+        for _ in range(0, 20) {
+            let line = recv_packet(&mut sock);
+
+            assert!(line.starts_with("svc.task") && line.ends_with(":1|c|#host:a"));
+            counts[line.char_at(8).to_digit(10).unwrap()] += 1;
+        }
+
+        assert_eq!(counts, [5, 5, 5, 5]);
+        assert_eq!(client.send_errors(), 0);
+    }
+
+    #[test]
+    fn test_empty_prefix_is_ignored() {
+        let (_sock, addr) = server();
+
+        assert_eq!(SharedClient::new_with_prefix("", addr).unwrap().prefix(), "");
+        assert_eq!(SharedClient::new_with_prefix("svc", addr).unwrap().prefix(), "svc.");
+    }
+
+    #[test]
+    fn test_send_errors_are_counted() {
+        let dest = SocketAddr { ip: Ipv4Addr(255, 255, 255, 255), port: 8125 };
+        let client = SharedClient::new(dest).unwrap();
+
+        client.incr("a", 1.0);
+        client.sink().gauge("b", 1.0);
+
+        assert_eq!(client.send_errors(), 2);
+    }
+
+    fn never_sample() -> ~Sampler {
+        box NeverSample as ~Sampler
+    }
+
+    #[test]
+    fn test_sampler_fn() {
+        let (mut sock, addr) = server();
+
+        let mut client = SharedClient::new(addr).unwrap();
+        client.set_sampler_fn(never_sample);
+
+        client.incr("sampled", 0.5);
+        client.incr("unsampled", 1.0);
+        assert_eq!(recv_packet(&mut sock), ~"unsampled:1|c");
+
+        let mut sink = client.sink();
+        sink.incr("sampled", 0.5);
+        sink.incr("unsampled", 1.0);
+        assert_eq!(recv_packet(&mut sock), ~"unsampled:1|c");
+    }
+
+    #[test]
+    fn test_metric_sink() {
+        let (mut sock, addr) = server();
+        let mut client = SharedClient::new(addr).unwrap();
+
+        {
+            let sink: &mut MetricSink = &mut client;
+            sink.gauge_incr("g", 2.0);
+        }
+        client.set("s", "user-7");
+
+        assert_eq!(recv_packet(&mut sock), ~"g:+2|g");
+        assert_eq!(recv_packet(&mut sock), ~"s:user-7|s");
+    }
+}
+
+
+#[cfg(test)]
+mod handle {
+    use statsd::handle::Registry;
+    use statsd::metric;
+    use statsd::metric::Metric;
+    use statsd::sampler::{Sampler, NeverSample};
+    use statsd::shared::SharedClient;
+
+    use std::from_str::FromStr;
+
+    use client::{server, recv_packet};
+
+    /// How `Metric::encode` would send the same metric.
+    fn encoded(kind: metric::MetricKind, name: &str, value: f64) -> ~str {
+        let mut metric = Metric::new(kind, name, value);
+        metric.tags.push((~"host", ~"a"));
+        metric.encode()
+    }
+
+    #[test]
+    fn test_handles_match_encode() {
+        let (mut sock, addr) = server();
+
+        let mut client = SharedClient::new_with_prefix("svc", addr).unwrap();
+        client.add_default_tag("host", "a");
+        let registry = Registry::new(client);
+
+        let mut counter = registry.counter("hits");
+        counter.incr();
+        assert_eq!(recv_packet(&mut sock), encoded(metric::Counter(1.0), "svc.hits", 1.0));
+        counter.count(-12.0);
+        assert_eq!(recv_packet(&mut sock), encoded(metric::Counter(1.0), "svc.hits", -12.0));
+
+        let mut gauge = registry.gauge("load");
+        gauge.set(0.25);
+        assert_eq!(recv_packet(&mut sock), encoded(metric::Gauge, "svc.load", 0.25));
+        gauge.set(-3.0);
+        assert_eq!(recv_packet(&mut sock), encoded(metric::Gauge, "svc.load", -3.0));
+        gauge.incr(2.0);
+        assert_eq!(recv_packet(&mut sock), encoded(metric::GaugeDelta, "svc.load", 2.0));
+        gauge.decr(5.0);
+        assert_eq!(recv_packet(&mut sock), encoded(metric::GaugeDelta, "svc.load", -5.0));
+
+        for &value in [0.1, 0.007, 2.0000001, 2.9999999, 1234.5678901, 1e16, 2e19].iter() {
+            gauge.set(value);
+            assert_eq!(recv_packet(&mut sock), encoded(metric::Gauge, "svc.load", value));
+        }
+
+        let mut timer = registry.timer("latency");
+        timer.record(1234567);
+        assert_eq!(recv_packet(&mut sock), encoded(metric::Timer(1.0), "svc.latency", 1234567.0));
+
+        let mut histogram = registry.histogram("size").clone();
+        histogram.record(10.5);
+        let packet = recv_packet(&mut sock);
+        assert_eq!(packet, encoded(metric::Histogram(1.0), "svc.size", 10.5));
+
+        let metric: Metric = FromStr::from_str(packet).unwrap();
+        assert_eq!(metric.name, ~"svc.size");
+        assert_eq!(metric.value, 10.5);
+    }
+
+    #[test]
+    fn test_sampled_counter() {
+        let (mut sock, addr) = server();
+
+        let registry = Registry::new(SharedClient::new(addr).unwrap());
+
+        let mut sampled = registry.counter_sampled("sampled", 0.5);
+        let mut done = registry.counter("done");
+
+        for _ in range(0, 200) {
+            sampled.incr();
+        }
+        done.incr();
+
+        // Each sample is sent with its rate, about half of the time.
+        let mut sent = 0;
+        loop {
+            let packet = recv_packet(&mut sock);
+
+            match packet.as_slice() {
+                "done:1|c" => break,
+                other => assert_eq!(other, "sampled:1|c|@0.5")
+            }
+            sent += 1;
+        }
+
+        assert!(sent > 60 && sent < 140);
+        assert_eq!(registry.client().send_errors(), 0);
+    }
+
+    fn never_sample() -> ~Sampler {
+        box NeverSample as ~Sampler
+    }
+
+    #[test]
+    fn test_sampler_fn() {
+        let (mut sock, addr) = server();
+
+        let mut client = SharedClient::new(addr).unwrap();
+        client.set_sampler_fn(never_sample);
+        let registry = Registry::new(client);
+
+        let mut sampled = registry.counter_sampled("sampled", 0.5).clone();
+        let mut done = registry.counter("done");
+
+        sampled.incr();
+        done.incr();
+        assert_eq!(recv_packet(&mut sock), ~"done:1|c");
+    }
+}
+
+
+#[cfg(test)]
+mod transport {
+    use statsd::client::Client;
+    use statsd::sink::MetricSink;
+    use statsd::transport::{Transport, TcpTransport, ResolvingUdpTransport, MIN_TCP_BACKOFF_MS};
+
+    use std::from_str::FromStr;
+    use std::io::{IoResult, Listener, Acceptor, BufferedReader};
+    use std::io::net::ip::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+    use std::io::net::tcp::TcpListener;
+    use std::io::net::udp::UdpSocket;
+    use std::io::timer::sleep;
+    use std::sync::atomics::{AtomicBool, AtomicUint, INIT_ATOMIC_BOOL, INIT_ATOMIC_UINT, SeqCst};
+
+    use time;
+
+    use client::{server, recv_packet};
+
+    static mut HOST_MOVED: AtomicBool = INIT_ATOMIC_BOOL;
+    static mut LOOKUPS: AtomicUint = INIT_ATOMIC_UINT;
+    static mut IPV6_LOOKUPS: AtomicUint = INIT_ATOMIC_UINT;
+
+    /// Resolves to 127.0.0.1 until `HOST_MOVED` is set, then to 127.0.0.2.
+    fn moving_resolver(_host: &str) -> IoResult<IpAddr> {
+        if unsafe { HOST_MOVED.load(SeqCst) } {
+            Ok(Ipv4Addr(127, 0, 0, 2))
+        } else {
+            Ok(Ipv4Addr(127, 0, 0, 1))
+        }
+    }
+
+    /// Resolves to an address that can't be sent to the first time, then to
+    /// 127.0.0.1.
+    fn broken_resolver(_host: &str) -> IoResult<IpAddr> {
+        if unsafe { LOOKUPS.fetch_add(1, SeqCst) } == 0 {
+            Ok(Ipv4Addr(255, 255, 255, 255))
+        } else {
+            Ok(Ipv4Addr(127, 0, 0, 1))
+        }
+    }
+
+    /// Resolves to ::1 the first time, then to 127.0.0.1.
+    fn ipv6_resolver(_host: &str) -> IoResult<IpAddr> {
+        if unsafe { IPV6_LOOKUPS.fetch_add(1, SeqCst) } == 0 {
+            Ok(Ipv6Addr(0, 0, 0, 0, 0, 0, 0, 1))
+        } else {
+            Ok(Ipv4Addr(127, 0, 0, 1))
+        }
+    }
+
+    /// Give the resolver task some time to swap in `addr`.
+    fn wait_for_dest(transport: &ResolvingUdpTransport, addr: SocketAddr) {
+        for _ in range(0, 200) {
+            if transport.dest() == addr {
+                return;
+            }
+
+            sleep(10);
+        }
+
+        fail!("destination never changed to {}", addr);
+    }
+
+    #[test]
+    fn test_tcp_newline_delimited() {
+        let addr: SocketAddr = FromStr::from_str("127.0.0.1:0").unwrap();
+        let mut listener = TcpListener::bind(addr).unwrap();
+        let addr = listener.socket_name().unwrap();
+        let mut acceptor = listener.listen();
+
+        let mut client = Client::new_tcp(addr);
+        client.incr("a", 1.0);
+        client.gauge("b", 2.0);
+
+        let mut reader = BufferedReader::new(acceptor.accept().unwrap());
+        assert_eq!(reader.read_line().unwrap(), ~"a:1|c\n");
+        assert_eq!(reader.read_line().unwrap(), ~"b:2|g\n");
+        assert_eq!(client.send_errors(), 0);
+    }
+
+    #[test]
+    fn test_tcp_bounded_while_disconnected() {
+        // Grab a free port, then stop listening on it.
+        let addr = {
+            let addr: SocketAddr = FromStr::from_str("127.0.0.1:0").unwrap();
+            let mut listener = TcpListener::bind(addr).unwrap();
+            listener.socket_name().unwrap()
+        };
+
+        let mut transport = TcpTransport::new_with_max_pending(addr, 16);
+
+        assert!(transport.send(bytes!("a:1|c")).is_err());
+        assert!(transport.send(bytes!("b:1|c")).is_err());
+        assert_eq!(transport.pending(), 12);
+
+        // There's no room for this one, so it's dropped.
+        assert!(transport.send(bytes!("c:1|c")).is_err());
+        assert_eq!(transport.pending(), 12);
+    }
+
+    #[test]
+    fn test_tcp_reconnects_after_backoff() {
+        // Grab a free port, then stop listening on it.
+        let addr = {
+            let addr: SocketAddr = FromStr::from_str("127.0.0.1:0").unwrap();
+            let mut listener = TcpListener::bind(addr).unwrap();
+            listener.socket_name().unwrap()
+        };
+
+        let mut transport = TcpTransport::new(addr);
+        assert!(transport.send(bytes!("a:1|c")).is_err());
+
+        // The server is back, but it's too soon to connect again, so this is
+        // only queued.
+        let mut acceptor = TcpListener::bind(addr).unwrap().listen();
+        assert!(transport.send(bytes!("b:1|c")).is_err());
+        assert_eq!(transport.pending(), 12);
+
+        sleep(MIN_TCP_BACKOFF_MS + 50);
+        transport.send(bytes!("c:1|c")).unwrap();
+        assert_eq!(transport.pending(), 0);
+
+        let mut reader = BufferedReader::new(acceptor.accept().unwrap());
+        assert_eq!(reader.read_line().unwrap(), ~"a:1|c\n");
+        assert_eq!(reader.read_line().unwrap(), ~"b:1|c\n");
+        assert_eq!(reader.read_line().unwrap(), ~"c:1|c\n");
+    }
+
+    #[test]
+    fn test_tcp_write_times_out() {
+        let addr: SocketAddr = FromStr::from_str("127.0.0.1:0").unwrap();
+        let mut listener = TcpListener::bind(addr).unwrap();
+        let addr = listener.socket_name().unwrap();
+        let _acceptor = listener.listen();
+
+        let mut transport = TcpTransport::new_with_max_pending(addr, 1 << 24);
+        transport.set_timeout(50);
+
+        // The connection is never accepted or read from, so the socket
+        // buffers fill up and a send eventually gives up, keeping what wasn't
+        // written.
+        let data = [0u8, ..65536];
+        let start = time::precise_time_ns();
+
+        while transport.send(data).is_ok() {
+            assert!(time::precise_time_ns() - start < 5000000000);
+        }
+
+        assert!(transport.pending() > 0);
+    }
+
+    #[test]
+    fn test_resolved_again_on_interval() {
+        let (mut old_sock, old_addr) = server();
+
+        let new_addr = SocketAddr { ip: Ipv4Addr(127, 0, 0, 2), port: old_addr.port };
+        let mut new_sock = UdpSocket::bind(new_addr).unwrap();
+
+        let mut transport = ResolvingUdpTransport::new_with_resolver(
+            "statsd", old_addr.port, 10, 1000, moving_resolver).unwrap();
+
+        transport.send(bytes!("a:1|c")).unwrap();
+        assert_eq!(recv_packet(&mut old_sock), ~"a:1|c");
+
+        unsafe { HOST_MOVED.store(true, SeqCst) };
+        wait_for_dest(&transport, new_addr);
+
+        transport.send(bytes!("b:1|c")).unwrap();
+        assert_eq!(recv_packet(&mut new_sock), ~"b:1|c");
+    }
+
+    #[test]
+    fn test_resolved_to_ipv6() {
+        let addr: SocketAddr = FromStr::from_str("[::1]:0").unwrap();
+        let mut sock = UdpSocket::bind(addr).unwrap();
+        let addr = sock.socket_name().unwrap();
+
+        let mut transport = ResolvingUdpTransport::new_with_resolver(
+            "statsd", addr.port, 10, 1000, ipv6_resolver).unwrap();
+
+        transport.send(bytes!("a:1|c")).unwrap();
+        assert_eq!(recv_packet(&mut sock), ~"a:1|c");
+
+        // Resolving to IPv4 later on doesn't replace the address, since the
+        // socket can't send there.
+        sleep(100);
+        assert!(unsafe { IPV6_LOOKUPS.load(SeqCst) } > 1);
+        assert_eq!(transport.dest(), addr);
+
+        transport.send(bytes!("b:1|c")).unwrap();
+        assert_eq!(recv_packet(&mut sock), ~"b:1|c");
+    }
+
+    #[test]
+    fn test_resolved_again_after_errors() {
+        let (mut sock, addr) = server();
+
+        let mut transport = ResolvingUdpTransport::new_with_resolver(
+            "statsd", addr.port, 3600000, 2, broken_resolver).unwrap();
+
+        assert!(transport.send(bytes!("a:1|c")).is_err());
+        assert!(transport.send(bytes!("a:1|c")).is_err());
+        wait_for_dest(&transport, addr);
+
+        transport.send(bytes!("b:1|c")).unwrap();
+        assert_eq!(recv_packet(&mut sock), ~"b:1|c");
+    }
+}
+
+
+#[cfg(test)]
+mod unix {
+    use statsd::client::Client;
+    use statsd::sink::MetricSink;
+    use statsd::unix::UnixDatagram;
+
+    use rand::random;
+    use std::io;
+    use std::io::fs;
+    use std::os;
+    use std::str;
+
+    fn socket_path() -> Path {
+        os::tmpdir().join(format!("statsd-test-{}.sock", random::<u32>()))
+    }
+
+    #[test]
+    fn test_unix_client_and_server() {
+        let path = socket_path();
+        let mode = io::UserRead | io::UserWrite;
+
+        {
+            let mut server = UnixDatagram::bind(&path, mode).unwrap();
+            assert!(path.exists());
+
+            let mut client = Client::new_unix(&path).unwrap();
+            client.incr("a", 1.0);
+
+            let mut buf = [0u8, ..64];
+            let nread = server.recv(buf).unwrap();
+            assert_eq!(str::from_utf8(buf.slice_to(nread)), Some("a:1|c"));
+            assert_eq!(client.send_errors(), 0);
+
+            // A socket that is still receiving isn't taken over.
+            assert!(UnixDatagram::bind(&path, mode).is_err());
+            assert!(path.exists());
+        }
+
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn test_unix_bind_replaces_stale_socket() {
+        let path = socket_path();
+        let moved = socket_path();
+        let mode = io::UserRead | io::UserWrite;
+
+        // Moving the socket file away before the socket is closed leaves it
+        // behind with nothing receiving on it.
+        {
+            let _server = UnixDatagram::bind(&moved, mode).unwrap();
+            fs::rename(&moved, &path).unwrap();
+        }
+        assert!(path.exists());
+
+        let mut server = UnixDatagram::bind(&path, mode).unwrap();
+        let mut client = Client::new_unix(&path).unwrap();
+        client.incr("a", 1.0);
+
+        let mut buf = [0u8, ..64];
+        let nread = server.recv(buf).unwrap();
+        assert_eq!(str::from_utf8(buf.slice_to(nread)), Some("a:1|c"));
+    }
+
+    #[test]
+    fn test_unix_bind_keeps_regular_files() {
+        let path = socket_path();
+        fs::File::create(&path).unwrap();
+
+        assert!(UnixDatagram::bind(&path, io::UserRead | io::UserWrite).is_err());
+        assert!(path.exists());
+
+        fs::unlink(&path).unwrap();
+    }
+}
+
+
+#[cfg(test)]
+mod bench {
+    use statsd::metric::{Metric, MetricRef};
+    use statsd::server::buckets::Buckets;
+
+    use test::BenchHarness;
+
+    static PACKET: &'static [u8] = bytes!(
+        "api.requests:1|c\napi.latency:12.5|ms|@0.1\nqueue.depth:42|g\n",
+        "api.errors:1|c|#env:prod,host:a\nusers:1234|s");
+
+    /// Counters, gauges, tags and known set members update existing series
+    /// without allocating.
+    static STEADY_PACKET: &'static [u8] = bytes!(
+        "api.requests:1|c\nqueue.depth:42|g\nqueue.depth:-2|g\n",
+        "api.errors:1|c|#env:prod,host:a\nusers:1234|s");
+
+    #[bench]
+    fn bench_parse_owned(bh: &mut BenchHarness) {
+        bh.iter(|| {
+            for line in PACKET.split(|b| *b == '\n' as u8) {
+                let _ = Metric::parse_bytes(line);
+            }
+        });
+        bh.bytes = PACKET.len() as u64;
+    }
+
+    #[bench]
+    fn bench_parse_borrowed(bh: &mut BenchHarness) {
+        bh.iter(|| {
+            for line in PACKET.split(|b| *b == '\n' as u8) {
+                let _ = MetricRef::parse_bytes(line);
+            }
+        });
+        bh.bytes = PACKET.len() as u64;
+    }
+
+    /// Owned parsing copies every name and tag before it reaches the buckets.
+    #[bench]
+    fn bench_add_owned(bh: &mut BenchHarness) {
+        let mut buckets = Buckets::new();
+
+        bh.iter(|| {
+            for line in PACKET.split(|b| *b == '\n' as u8) {
+                let _ = Metric::parse_bytes(line).map(|m| buckets.add_metric(m));
+            }
+        });
+        bh.bytes = PACKET.len() as u64;
+    }
+
+    /// Borrowed parsing only allocates keys for the first packet, since every
+    /// following packet updates existing series.
+    #[bench]
+    fn bench_add_packet(bh: &mut BenchHarness) {
+        let mut buckets = Buckets::new();
+
+        bh.iter(|| buckets.add_packet(PACKET));
+        bh.bytes = PACKET.len() as u64;
+    }
+
+    #[bench]
+    fn bench_add_steady_packet(bh: &mut BenchHarness) {
+        let mut buckets = Buckets::new();
+        buckets.add_packet(STEADY_PACKET);
+
+        bh.iter(|| buckets.add_packet(STEADY_PACKET));
+        bh.bytes = STEADY_PACKET.len() as u64;
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,417 @@
+//! The ways a `Client` can get its data to a statsd server.
+
+use std::cmp;
+use std::comm::{Sender, Receiver, channel};
+use std::io;
+use std::io::{IoError, IoResult, Timer};
+use std::io::net::addrinfo;
+use std::io::net::ip::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::io::net::udp::UdpSocket;
+use std::mem;
+
+use sync::{Arc, RWLock};
+
+use tcp::TcpSender;
+use unix::UnixDatagram;
+
+use time;
+
+
+/// Default number of bytes a `TcpTransport` holds on to while it can't
+/// reach the server.
+pub static DEFAULT_TCP_PENDING: uint = 65536;
+
+/// Shortest and longest time a `TcpTransport` waits before connecting again
+/// after failing to connect.
+pub static MIN_TCP_BACKOFF_MS: u64 = 100;
+pub static MAX_TCP_BACKOFF_MS: u64 = 30000;
+
+/// Default time a `TcpTransport` waits for the server while connecting or
+/// writing before giving up.
+pub static DEFAULT_TCP_TIMEOUT_MS: u64 = 1000;
+
+/// Default number of sends in a row that can fail before a
+/// `ResolvingUdpTransport` resolves its host again.
+pub static DEFAULT_RESOLVE_ERRORS: uint = 3;
+
+
+/// Something that can deliver packets of newline separated metrics.
+pub trait Transport {
+    /// Send `data`, which holds one or more metrics separated by newlines.
+    fn send(&mut self, data: &[u8]) -> IoResult<()>;
+}
+
+
+/// Send each packet as a single UDP datagram.
+pub struct UdpTransport {
+    dest: SocketAddr,
+    sock: UdpSocket
+}
+
+
+impl UdpTransport {
+    pub fn new(dest: SocketAddr) -> IoResult<UdpTransport> {
+        let sock = try!(bind_udp_for(dest));
+
+        Ok(UdpTransport { dest: dest, sock: sock })
+    }
+}
+
+
+/// Bind a UDP socket to any local port, with the address family of `dest` so
+/// that it can send there.
+fn bind_udp_for(dest: SocketAddr) -> IoResult<UdpSocket> {
+    let ip = match dest.ip {
+        Ipv4Addr(..) => Ipv4Addr(0, 0, 0, 0),
+        Ipv6Addr(..) => Ipv6Addr(0, 0, 0, 0, 0, 0, 0, 0)
+    };
+
+    UdpSocket::bind(SocketAddr { ip: ip, port: 0 })
+}
+
+
+impl Transport for UdpTransport {
+    fn send(&mut self, data: &[u8]) -> IoResult<()> {
+        self.sock.sendto(data, self.dest)
+    }
+}
+
+
+/// Look up the first address of `host`.
+pub fn resolve_host(host: &str) -> IoResult<IpAddr> {
+    let addrs = try!(addrinfo::get_host_addresses(host));
+
+    match addrs.head() {
+        Some(&ip) => Ok(ip),
+        None => Err(IoError {
+            kind: io::OtherIoError,
+            desc: "host has no addresses",
+            detail: Some(host.to_owned())
+        })
+    }
+}
+
+
+/// What the resolver task of a `ResolvingUdpTransport` is asked to do.
+enum ResolverCommand {
+    ResolveNow,
+    StopResolving
+}
+
+
+/** Send each packet as a single UDP datagram to a host given by name.
+
+The host is resolved again in a background task every so often, and after a
+number of sends in a row have failed. When its address changes, the new one is
+swapped in without holding up sends. If it can't be resolved, or resolves to
+an address of the other family (IPv4 or IPv6) than the one it was first
+resolved to, the last known address keeps being used.
+*/
+pub struct ResolvingUdpTransport {
+    dest: Arc<RWLock<SocketAddr>>,
+    sock: UdpSocket,
+    resolver: Sender<ResolverCommand>,
+
+    errors: uint, // failed sends in a row
+    max_errors: uint
+}
+
+
+impl ResolvingUdpTransport {
+    /// Resolve `host` now, then again every `interval_ms`, or after
+    /// `max_errors` sends in a row have failed.
+    pub fn new(host: &str, port: u16, interval_ms: u64,
+               max_errors: uint) -> IoResult<ResolvingUdpTransport> {
+        ResolvingUdpTransport::new_with_resolver(host, port, interval_ms, max_errors, resolve_host)
+    }
+
+    /// Like `new`, but starting with `dest`, which `host` has already been
+    /// resolved to.
+    pub fn new_resolved(host: &str, dest: SocketAddr, interval_ms: u64,
+                        max_errors: uint) -> IoResult<ResolvingUdpTransport> {
+        ResolvingUdpTransport::start(host, dest, interval_ms, max_errors, resolve_host)
+    }
+
+    /// Like `new`, but looking the host up with `resolve`.
+    pub fn new_with_resolver(host: &str, port: u16, interval_ms: u64, max_errors: uint,
+                             resolve: fn(&str) -> IoResult<IpAddr>)
+                             -> IoResult<ResolvingUdpTransport> {
+        let ip = try!(resolve(host));
+
+        ResolvingUdpTransport::start(host, SocketAddr { ip: ip, port: port },
+                                     interval_ms, max_errors, resolve)
+    }
+
+    /// Send to `dest` for now, and start resolving `host` in the background.
+    fn start(host: &str, dest: SocketAddr, interval_ms: u64, max_errors: uint,
+             resolve: fn(&str) -> IoResult<IpAddr>) -> IoResult<ResolvingUdpTransport> {
+        let port = dest.port;
+        let sock = try!(bind_udp_for(dest));
+
+        let dest = Arc::new(RWLock::new(dest));
+        let (resolver, commands) = channel();
+
+        let ticks = resolver.clone();
+        spawn(proc() { resolve_timer_loop(ticks, interval_ms) });
+
+        let task_dest = dest.clone();
+        let host = host.to_owned();
+        spawn(proc() { resolve_loop(host, port, resolve, task_dest, commands) });
+
+        Ok(ResolvingUdpTransport {
+            dest: dest,
+            sock: sock,
+            resolver: resolver,
+
+            errors: 0,
+            max_errors: max_errors
+        })
+    }
+
+    /// The address packets are currently sent to.
+    pub fn dest(&self) -> SocketAddr {
+        *self.dest.read()
+    }
+}
+
+
+impl Transport for ResolvingUdpTransport {
+    fn send(&mut self, data: &[u8]) -> IoResult<()> {
+        let dest = self.dest();
+        let result = self.sock.sendto(data, dest);
+
+        if result.is_ok() {
+            self.errors = 0;
+        } else {
+            self.errors += 1;
+
+            if self.errors >= self.max_errors {
+                let _ = self.resolver.send_opt(ResolveNow);
+                self.errors = 0;
+            }
+        }
+
+        result
+    }
+}
+
+
+impl Drop for ResolvingUdpTransport {
+    fn drop(&mut self) {
+        let _ = self.resolver.send_opt(StopResolving);
+    }
+}
+
+
+/// Ask for the host to be resolved again every `interval_ms`, until the
+/// resolver task is gone.
+fn resolve_timer_loop(ticks: Sender<ResolverCommand>, interval_ms: u64) {
+    let mut timer = Timer::new().unwrap();
+    let periodic = timer.periodic(interval_ms);
+
+    loop {
+        periodic.recv();
+
+        if ticks.send_opt(ResolveNow).is_err() {
+            return;
+        }
+    }
+}
+
+
+/// Resolve `host` whenever asked to, swapping in its new address.
+fn resolve_loop(host: ~str, port: u16, resolve: fn(&str) -> IoResult<IpAddr>,
+                dest: Arc<RWLock<SocketAddr>>, commands: Receiver<ResolverCommand>) {
+    for command in commands.iter() {
+        match command {
+            ResolveNow => match resolve(host.as_slice()) {
+                // The socket can only send to addresses of the family it was
+                // bound with.
+                Ok(ip) if same_family(ip, dest.read().ip) => {
+                    *dest.write() = SocketAddr { ip: ip, port: port }
+                },
+                // Keep using the last known address.
+                _ => {}
+            },
+            StopResolving => return
+        }
+    }
+}
+
+
+fn same_family(a: IpAddr, b: IpAddr) -> bool {
+    match (a, b) {
+        (Ipv4Addr(..), Ipv4Addr(..)) | (Ipv6Addr(..), Ipv6Addr(..)) => true,
+        _ => false
+    }
+}
+
+
+/// Send each packet as a single datagram to a Unix domain socket, for a
+/// server running on the same host.
+pub struct UnixTransport {
+    path: Path,
+    sock: UnixDatagram
+}
+
+
+impl UnixTransport {
+    pub fn new(path: &Path) -> IoResult<UnixTransport> {
+        let sock = try!(UnixDatagram::unbound());
+
+        Ok(UnixTransport { path: path.clone(), sock: sock })
+    }
+}
+
+
+impl Transport for UnixTransport {
+    fn send(&mut self, data: &[u8]) -> IoResult<()> {
+        self.sock.send_to(data, &self.path)
+    }
+}
+
+
+/** Send newline terminated metrics over a TCP stream.
+
+The connection is only made when there is something to send, and is dropped
+whenever a write fails, to be made again on the next send. If connecting
+fails, the next attempt waits for a backoff that doubles with every failure,
+and sends in the meantime are only queued. Connecting and writing give up
+once the server has kept them waiting for the timeout, so a server that
+stops responding only holds up a send for that long.
+
+Data that couldn't be written is kept (up to a limit) and sent ahead of
+anything else once the connection is back. It is kept per send, so that only
+a send that wasn't written in full is sent again.
+*/
+pub struct TcpTransport {
+    dest: SocketAddr,
+    stream: Option<TcpSender>,
+    pending: ~[~[u8]],
+    pending_bytes: uint,
+    max_pending: uint,
+    timeout_ms: u64,
+
+    backoff_ms: u64, // 0 until connecting fails
+    retry_at_ns: u64
+}
+
+
+impl TcpTransport {
+    pub fn new(dest: SocketAddr) -> TcpTransport {
+        TcpTransport::new_with_max_pending(dest, DEFAULT_TCP_PENDING)
+    }
+
+    /// Create a transport holding on to at most `max_pending` bytes while
+    /// disconnected.
+    pub fn new_with_max_pending(dest: SocketAddr, max_pending: uint) -> TcpTransport {
+        TcpTransport {
+            dest: dest,
+            stream: None,
+            pending: ~[],
+            pending_bytes: 0,
+            max_pending: max_pending,
+            timeout_ms: DEFAULT_TCP_TIMEOUT_MS,
+
+            backoff_ms: 0,
+            retry_at_ns: 0
+        }
+    }
+
+    /// Wait at most `timeout_ms` for the server while connecting or writing.
+    pub fn set_timeout(&mut self, timeout_ms: u64) {
+        self.timeout_ms = timeout_ms;
+    }
+
+    /// Number of bytes waiting to be sent.
+    pub fn pending(&self) -> uint {
+        self.pending_bytes
+    }
+
+    /// Connect, unless the last attempt failed too recently.
+    fn connect(&mut self) -> IoResult<()> {
+        if time::precise_time_ns() < self.retry_at_ns {
+            return Err(IoError {
+                kind: io::NotConnected,
+                desc: "waiting to reconnect",
+                detail: None
+            });
+        }
+
+        match TcpSender::connect(self.dest, self.timeout_ms) {
+            Ok(stream) => {
+                self.stream = Some(stream);
+                self.backoff_ms = 0;
+                Ok(())
+            },
+            Err(err) => {
+                self.backoff_ms = match self.backoff_ms {
+                    0 => MIN_TCP_BACKOFF_MS,
+                    ms => cmp::min(ms * 2, MAX_TCP_BACKOFF_MS)
+                };
+                self.retry_at_ns = time::precise_time_ns() + self.backoff_ms * 1000000;
+                Err(err)
+            }
+        }
+    }
+
+    fn write_pending(&mut self) -> IoResult<()> {
+        if self.stream.is_none() {
+            try!(self.connect());
+        }
+
+        let mut written = 0;
+        let result = {
+            let stream = self.stream.get_mut_ref();
+            let mut result = Ok(());
+
+            for data in self.pending.iter() {
+                result = stream.write(data.as_slice());
+                if result.is_err() {
+                    break;
+                }
+
+                written += 1;
+            }
+
+            result
+        };
+
+        let pending = mem::replace(&mut self.pending, ~[]);
+        for data in pending.move_iter().skip(written) {
+            self.pending.push(data);
+        }
+        self.pending_bytes = self.pending.iter().fold(0, |total, data| total + data.len());
+
+        if result.is_err() {
+            // Reconnect on the next send.
+            self.stream = None;
+        }
+
+        result
+    }
+}
+
+
+impl Transport for TcpTransport {
+    fn send(&mut self, data: &[u8]) -> IoResult<()> {
+        if self.pending_bytes + data.len() + 1 > self.max_pending {
+            // Still try to get rid of what's already pending.
+            let _ = self.write_pending();
+
+            return Err(IoError {
+                kind: io::ResourceUnavailable,
+                desc: "too much data pending, dropping metrics",
+                detail: None
+            });
+        }
+
+        let mut line = data.to_owned();
+        line.push('\n' as u8);
+
+        self.pending_bytes += line.len();
+        self.pending.push(line);
+
+        self.write_pending()
+    }
+}
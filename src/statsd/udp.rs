@@ -0,0 +1,96 @@
+//! UDP sockets that can send from several tasks at once, which the standard
+//! library doesn't provide.
+
+use libc::{c_int, c_void, size_t, socklen_t, sa_family_t};
+use libc::{sockaddr, sockaddr_storage, sockaddr_in, sockaddr_in6, in_addr, in6_addr};
+use libc::{AF_INET, AF_INET6, SOCK_DGRAM};
+use libc::{socket, sendto, close};
+
+use std::cast;
+use std::io::{IoError, IoResult};
+use std::io::net::ip::{Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::mem;
+
+
+/// Build the address of `addr`, along with its length.
+pub fn sockaddr(addr: SocketAddr) -> (sockaddr_storage, socklen_t) {
+    let mut storage: sockaddr_storage = unsafe { mem::init() };
+    let port = mem::to_be16(addr.port);
+
+    let len = match addr.ip {
+        Ipv4Addr(a, b, c, d) => {
+            let sin: &mut sockaddr_in = unsafe { cast::transmute(&mut storage) };
+            let ip = (a as u32 << 24) | (b as u32 << 16) | (c as u32 << 8) | d as u32;
+
+            sin.sin_family = AF_INET as sa_family_t;
+            sin.sin_port = port;
+            sin.sin_addr = in_addr { s_addr: mem::to_be32(ip) };
+            mem::size_of::<sockaddr_in>()
+        },
+        Ipv6Addr(a, b, c, d, e, f, g, h) => {
+            let sin6: &mut sockaddr_in6 = unsafe { cast::transmute(&mut storage) };
+
+            sin6.sin6_family = AF_INET6 as sa_family_t;
+            sin6.sin6_port = port;
+            sin6.sin6_addr = in6_addr {
+                s6_addr: [mem::to_be16(a), mem::to_be16(b), mem::to_be16(c), mem::to_be16(d),
+                          mem::to_be16(e), mem::to_be16(f), mem::to_be16(g), mem::to_be16(h)]
+            };
+            mem::size_of::<sockaddr_in6>()
+        }
+    };
+
+    (storage, len as socklen_t)
+}
+
+
+/// A UDP socket sending datagrams to a single destination.
+///
+/// Sending only needs a shared reference, so one socket can be used from
+/// several tasks at once without locking.
+pub struct UdpSender {
+    fd: c_int,
+    dest: sockaddr_storage,
+    dest_len: socklen_t
+}
+
+
+impl UdpSender {
+    /// Create a socket sending to `dest`.
+    pub fn new(dest: SocketAddr) -> IoResult<UdpSender> {
+        let domain = match dest.ip {
+            Ipv4Addr(..) => AF_INET,
+            Ipv6Addr(..) => AF_INET6
+        };
+        let (addr, len) = sockaddr(dest);
+
+        let fd = unsafe { socket(domain, SOCK_DGRAM, 0) };
+
+        if fd < 0 {
+            Err(IoError::last_error())
+        } else {
+            Ok(UdpSender { fd: fd, dest: addr, dest_len: len })
+        }
+    }
+
+    /// Send `buf` as a single datagram.
+    pub fn send(&self, buf: &[u8]) -> IoResult<()> {
+        let ret = unsafe {
+            sendto(self.fd, buf.as_ptr() as *c_void, buf.len() as size_t, 0,
+                   &self.dest as *sockaddr_storage as *sockaddr, self.dest_len)
+        };
+
+        if ret < 0 {
+            Err(IoError::last_error())
+        } else {
+            Ok(())
+        }
+    }
+}
+
+
+impl Drop for UdpSender {
+    fn drop(&mut self) {
+        unsafe { close(self.fd); }
+    }
+}
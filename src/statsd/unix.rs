@@ -0,0 +1,157 @@
+//! Unix domain datagram sockets, which the standard library doesn't provide.
+
+use libc::{c_char, c_int, c_void, size_t, socklen_t, sa_family_t};
+use libc::{sockaddr, sockaddr_un, AF_UNIX, SOCK_DGRAM};
+use libc::{socket, bind, connect, sendto, recv, close};
+
+use std::io;
+use std::io::{IoError, IoResult, FilePermission};
+use std::io::fs;
+use std::mem;
+
+
+/// Build the address of the socket at `path`, along with its length.
+fn sockaddr(path: &Path) -> IoResult<(sockaddr_un, socklen_t)> {
+    let bytes = path.as_vec();
+    let mut addr: sockaddr_un = unsafe { mem::init() };
+
+    // Leave room for a terminating null byte.
+    if bytes.len() >= addr.sun_path.len() || bytes.contains(&0) {
+        return Err(IoError {
+            kind: io::InvalidInput,
+            desc: "invalid unix socket path",
+            detail: Some(path.display().to_str())
+        });
+    }
+
+    addr.sun_family = AF_UNIX as sa_family_t;
+    for (slot, b) in addr.sun_path.mut_iter().zip(bytes.iter()) {
+        *slot = *b as c_char;
+    }
+
+    let len = mem::size_of::<sa_family_t>() + bytes.len() + 1;
+
+    Ok((addr, len as socklen_t))
+}
+
+
+/// Whether nothing is receiving on the socket file at `path` anymore.
+fn is_stale(path: &Path) -> IoResult<bool> {
+    let (addr, len) = try!(sockaddr(path));
+    let sock = try!(UnixDatagram::unbound());
+
+    let ret = unsafe {
+        connect(sock.fd, &addr as *sockaddr_un as *sockaddr, len)
+    };
+
+    if ret == 0 {
+        return Ok(false);
+    }
+
+    let err = IoError::last_error();
+    if err.kind == io::ConnectionRefused {
+        Ok(true)
+    } else {
+        Err(err)
+    }
+}
+
+
+/// A Unix domain datagram socket.
+///
+/// A socket that has been bound to a path removes the socket file again when
+/// it is dropped.
+pub struct UnixDatagram {
+    fd: c_int,
+    path: Option<Path>
+}
+
+
+impl UnixDatagram {
+    /// Create a socket that can only be used to send.
+    pub fn unbound() -> IoResult<UnixDatagram> {
+        let fd = unsafe { socket(AF_UNIX, SOCK_DGRAM, 0) };
+
+        if fd < 0 {
+            Err(IoError::last_error())
+        } else {
+            Ok(UnixDatagram { fd: fd, path: None })
+        }
+    }
+
+    /// Create a socket receiving datagrams sent to `path`, which is given the
+    /// permissions in `mode`.
+    ///
+    /// A socket file left behind at `path` by a server that is gone (one
+    /// that didn't shut down cleanly, for instance) is removed first. Binding
+    /// fails if something is still receiving on it, or if `path` is any other
+    /// kind of file.
+    pub fn bind(path: &Path, mode: FilePermission) -> IoResult<UnixDatagram> {
+        let (addr, len) = try!(sockaddr(path));
+
+        match fs::lstat(path) {
+            Ok(ref stat) if stat.kind == io::TypeUnknown => {
+                if try!(is_stale(path)) {
+                    try!(fs::unlink(path));
+                }
+            },
+            _ => {}
+        }
+
+        let mut sock = try!(UnixDatagram::unbound());
+
+        let ret = unsafe {
+            bind(sock.fd, &addr as *sockaddr_un as *sockaddr, len)
+        };
+
+        if ret < 0 {
+            return Err(IoError::last_error());
+        }
+
+        sock.path = Some(path.clone());
+        try!(fs::chmod(path, mode));
+
+        Ok(sock)
+    }
+
+    /// Send `buf` as a single datagram to the socket bound at `path`.
+    pub fn send_to(&mut self, buf: &[u8], path: &Path) -> IoResult<()> {
+        let (addr, len) = try!(sockaddr(path));
+
+        let ret = unsafe {
+            sendto(self.fd, buf.as_ptr() as *c_void, buf.len() as size_t, 0,
+                   &addr as *sockaddr_un as *sockaddr, len)
+        };
+
+        if ret < 0 {
+            Err(IoError::last_error())
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Receive a single datagram into `buf`, returning the number of bytes
+    /// read.
+    pub fn recv(&mut self, buf: &mut [u8]) -> IoResult<uint> {
+        let ret = unsafe {
+            recv(self.fd, buf.as_mut_ptr() as *mut c_void, buf.len() as size_t, 0)
+        };
+
+        if ret < 0 {
+            Err(IoError::last_error())
+        } else {
+            Ok(ret as uint)
+        }
+    }
+}
+
+
+impl Drop for UnixDatagram {
+    fn drop(&mut self) {
+        unsafe { close(self.fd); }
+
+        for path in self.path.iter() {
+            let _ = fs::unlink(path);
+        }
+    }
+}